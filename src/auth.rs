@@ -0,0 +1,426 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{Arc, Mutex},
+};
+
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use sha2::{Digest, Sha256};
+use tokio::{
+    select,
+    signal::unix::{signal, SignalKind},
+    sync::Semaphore,
+};
+
+use crate::{
+    secret::{Secret, Token},
+    AppState, Config, Error,
+};
+
+/// Something a token can be allowed to do.
+#[derive(Clone, Copy)]
+pub enum Scope<'a> {
+    /// Restart a composition or its services, or cancel its jobs
+    Restart(&'a str),
+    /// Read a composition's jobs and logs
+    Status(&'a str),
+    Prune,
+    Freeze,
+}
+
+impl Scope<'_> {
+    fn kind(self) -> &'static str {
+        match self {
+            Self::Restart(_) => "restart",
+            Self::Status(_) => "status",
+            Self::Prune => "prune",
+            Self::Freeze => "freeze",
+        }
+    }
+
+    fn composition(&self) -> Option<&str> {
+        match self {
+            Self::Restart(name) | Self::Status(name) => Some(name),
+            Self::Prune | Self::Freeze => None,
+        }
+    }
+}
+
+impl Display for Scope<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.composition() {
+            Some(name) => write!(f, "{}:{name}", self.kind()),
+            None => f.write_str(self.kind()),
+        }
+    }
+}
+
+/// A token with a name to log, and a list of scopes like `restart:prod`,
+/// `status:*`, `prune` or `freeze`.
+#[derive(serde::Deserialize)]
+pub struct NamedToken {
+    token: Token,
+    scopes: Vec<String>,
+}
+
+/// Who presented a token, and what it lets them do.
+pub struct Identity {
+    pub name: String,
+    scopes: Vec<String>,
+}
+
+impl Identity {
+    pub fn new(name: String, scopes: Vec<String>) -> Self {
+        Self { name, scopes }
+    }
+
+    pub fn may(&self, scope: Scope) -> bool {
+        self.scopes.iter().any(|granted| {
+            let (kind, target) = granted.split_once(':').unwrap_or((granted, "*"));
+            granted == "*"
+                || (kind == scope.kind()
+                    && (target == "*" || scope.composition().is_none_or(|name| name == target)))
+        })
+    }
+
+    /// Check that this identity may do `scope`, leaving an audit trail either way.
+    pub fn check(&self, scope: Scope) -> Result<(), Error> {
+        if !self.may(scope) {
+            println!("[audit] {} denied {scope}", self.name);
+            return Err(Error::Forbidden);
+        }
+        println!("[audit] {} {scope}", self.name);
+        Ok(())
+    }
+}
+
+/// Every token the config defines.
+pub struct Tokens {
+    entries: Vec<(Token, Arc<Identity>)>,
+    /// Who the tokens already checked against slow hashes belong to, by the
+    /// tokens' SHA-256, so each is only checked slowly once per reload
+    verified: Mutex<HashMap<[u8; 32], Arc<Identity>>>,
+}
+
+/// At most this many slow hashes are checked at once, so a flood of bad
+/// tokens can't take every blocking thread and the memory argon2 needs.
+static SLOW_CHECKS: Semaphore = Semaphore::const_new(2);
+
+const KINDS: [&str; 4] = ["restart", "status", "prune", "freeze"];
+
+impl Tokens {
+    /// The global `token` may do anything, a composition's `token` may restart
+    /// it and read its status, and named `tokens` may do what their scopes say.
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        let mut entries = Vec::new();
+        let mut add = |token: Token, name: String, scopes: Vec<String>| {
+            let identity = Arc::new(Identity { name, scopes });
+            entries.push((token, identity));
+        };
+        if let Some(token) = either(&config.token, &config.token_file, "token")? {
+            add(token, "global".to_owned(), vec!["*".to_owned()]);
+        }
+        for (name, composition) in &config.extra {
+            if let Some(token) = either(&composition.token, &composition.token_file, name)? {
+                let scopes = vec![format!("restart:{name}"), format!("status:{name}")];
+                add(token, format!("composition:{name}"), scopes);
+            }
+        }
+        for (name, named) in &config.tokens {
+            check_scopes(&format!("token {name}"), &named.scopes)?;
+            add(named.token.clone(), name.clone(), named.scopes.clone());
+        }
+        Ok(Self {
+            entries,
+            verified: Mutex::default(),
+        })
+    }
+
+    /// Find who `token` belongs to among the tokens that are quick to check,
+    /// comparing against every one in constant time so timing doesn't hint at
+    /// which one nearly matched, or among the slow ones already checked.
+    fn identify(&self, token: &str) -> Option<Arc<Identity>> {
+        let mut found = None;
+        for (candidate, identity) in &self.entries {
+            if !candidate.is_slow() && candidate.matches(token) {
+                found = Some(identity.clone());
+            }
+        }
+        found.or_else(|| self.verified().get(&digest(token)).cloned())
+    }
+
+    /// The tokens that are slow to check, to check without holding the lock.
+    fn slow(&self) -> Vec<(Token, Arc<Identity>)> {
+        self.entries
+            .iter()
+            .filter(|(candidate, _)| candidate.is_slow())
+            .cloned()
+            .collect()
+    }
+
+    /// Skip the slow check next time `token` is presented, unless `identity`
+    /// was reloaded away while it was being checked.
+    fn remember(&self, token: &str, identity: &Arc<Identity>) {
+        if self
+            .entries
+            .iter()
+            .any(|(_, known)| Arc::ptr_eq(known, identity))
+        {
+            self.verified().insert(digest(token), identity.clone());
+        }
+    }
+
+    fn verified(&self) -> std::sync::MutexGuard<'_, HashMap<[u8; 32], Arc<Identity>>> {
+        self.verified.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn digest(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+/// Check that `scopes` only name things a token can be allowed to do.
+pub fn check_scopes(owner: &str, scopes: &[String]) -> Result<(), String> {
+    for scope in scopes {
+        let kind = scope
+            .split_once(':')
+            .map_or(scope.as_str(), |(kind, _)| kind);
+        if scope != "*" && !KINDS.contains(&kind) {
+            return Err(format!("{owner} has unknown scope {scope}"));
+        }
+    }
+    Ok(())
+}
+
+/// `token`, or the one in `token_file`, but not both.
+fn either(
+    token: &Option<Token>,
+    file: &Option<Secret>,
+    owner: &str,
+) -> Result<Option<Token>, String> {
+    match (token, file) {
+        (Some(_), Some(_)) => Err(format!("{owner} has both token and token_file")),
+        (token, file) => Ok(token.clone().or(file.clone().map(Token::from))),
+    }
+}
+
+/// Find who `token` belongs to, or fail as unauthorized. Tokens with slow
+/// hashes are checked on a blocking thread, without holding up reloads.
+pub async fn authenticate(state: &AppState, token: &str) -> Result<Arc<Identity>, Error> {
+    let slow = {
+        let tokens = state.tokens.read().unwrap_or_else(|e| e.into_inner());
+        if let Some(identity) = tokens.identify(token) {
+            return Ok(identity);
+        }
+        tokens.slow()
+    };
+    if slow.is_empty() {
+        return Err(Error::Unauthorized);
+    }
+    let _permit = SLOW_CHECKS
+        .acquire()
+        .await
+        .map_err(|_| Error::Unauthorized)?;
+    let presented = token.to_owned();
+    let found = tokio::task::spawn_blocking(move || {
+        slow.into_iter()
+            .find(|(candidate, _)| candidate.matches(&presented))
+            .map(|(_, identity)| identity)
+    })
+    .await
+    .ok()
+    .flatten()
+    .ok_or(Error::Unauthorized)?;
+    let tokens = state.tokens.read().unwrap_or_else(|e| e.into_inner());
+    tokens.remember(token, &found);
+    Ok(found)
+}
+
+/// Who a TLS client certificate belongs to, added to every request made over
+/// that connection.
+#[derive(Clone)]
+pub struct ClientIdentity(pub Arc<Identity>);
+
+/// Find who made a request: the owner of `token` if there is one, otherwise
+/// of the client certificate the connection was made with.
+pub async fn identify(
+    state: &AppState,
+    token: Option<&str>,
+    client: Option<&ClientIdentity>,
+) -> Result<Arc<Identity>, Error> {
+    match (token, client) {
+        (Some(token), _) => authenticate(state, token).await,
+        (None, Some(ClientIdentity(identity))) => Ok(identity.clone()),
+        (None, None) => Err(Error::Unauthorized),
+    }
+}
+
+/// Whoever made a request, by bearer token or client certificate.
+pub struct Caller(pub Arc<Identity>);
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Caller {
+    type Rejection = Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let bearer = Option::<TypedHeader<Authorization<Bearer>>>::from_request_parts(parts, state)
+            .await
+            .ok()
+            .flatten();
+        let token = bearer
+            .as_ref()
+            .map(|TypedHeader(Authorization(bearer))| bearer.token());
+        let client = parts.extensions.get::<ClientIdentity>();
+        identify(state, token, client).await.map(Self)
+    }
+}
+
+/// Re-read the tokens from the config file on every SIGHUP, so they can be
+/// revoked without a restart. Nothing else in the config is reloaded.
+pub async fn reload_on_hangup(state: Arc<AppState>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(source) => {
+            eprintln!("Error: {source:?}");
+            return;
+        }
+    };
+    loop {
+        select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = hangup.recv() => {}
+        }
+        match reload(&state) {
+            Ok(count) => println!("Reloaded {count} tokens from {}", state.config_path),
+            Err(message) => eprintln!("Error reloading {}: {message}", state.config_path),
+        }
+    }
+}
+
+fn reload(state: &AppState) -> Result<usize, String> {
+    let contents = std::fs::read_to_string(&state.config_path).map_err(|e| e.to_string())?;
+    let config: Config = toml::from_str(&contents).map_err(|e| e.to_string())?;
+    let tokens = Tokens::from_config(&config)?;
+    let count = tokens.entries.len();
+    *state.tokens.write().unwrap_or_else(|e| e.into_inner()) = tokens;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::{
+        password_hash::{rand_core::OsRng, SaltString},
+        Argon2, PasswordHasher,
+    };
+
+    use super::*;
+
+    fn tokens(entries: Vec<(Token, &str)>) -> Tokens {
+        let entries = entries
+            .into_iter()
+            .map(|(token, name)| (token, Arc::new(Identity::new(name.to_owned(), Vec::new()))))
+            .collect();
+        Tokens {
+            entries,
+            verified: Mutex::default(),
+        }
+    }
+
+    fn argon2(token: &str) -> Token {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(token.as_bytes(), &salt)
+            .unwrap();
+        toml::from_str::<NamedToken>(&format!("token = {{ argon2 = \"{hash}\" }}\nscopes = []"))
+            .unwrap()
+            .token
+    }
+
+    fn identity(scopes: &[&str]) -> Identity {
+        let scopes = scopes.iter().map(|scope| (*scope).to_owned()).collect();
+        Identity::new("test".to_owned(), scopes)
+    }
+
+    #[test]
+    fn scopes_name_a_kind_and_composition() {
+        let ci = identity(&["restart:prod", "status:*"]);
+        assert!(ci.may(Scope::Restart("prod")));
+        assert!(!ci.may(Scope::Restart("staging")));
+        assert!(ci.may(Scope::Status("prod")));
+        assert!(ci.may(Scope::Status("staging")));
+        assert!(!ci.may(Scope::Prune));
+        assert!(!ci.may(Scope::Freeze));
+    }
+
+    #[test]
+    fn global_scopes_cover_everything_of_their_kind() {
+        let janitor = identity(&["prune", "freeze"]);
+        assert!(janitor.may(Scope::Prune));
+        assert!(janitor.may(Scope::Freeze));
+        assert!(!janitor.may(Scope::Status("prod")));
+        // A kind without a composition is the same as `kind:*`
+        assert!(identity(&["restart"]).may(Scope::Restart("prod")));
+
+        let admin = identity(&["*"]);
+        assert!(admin.may(Scope::Restart("prod")));
+        assert!(admin.may(Scope::Prune));
+        assert!(!identity(&[]).may(Scope::Status("prod")));
+    }
+
+    #[test]
+    fn unknown_scopes_are_rejected() {
+        let scopes = |scopes: &[&str]| -> Vec<String> {
+            scopes.iter().map(|scope| (*scope).to_owned()).collect()
+        };
+        assert!(check_scopes("ci", &scopes(&["restart:prod", "status:*", "prune", "*"])).is_ok());
+        assert_eq!(
+            check_scopes("ci", &scopes(&["deploy:prod"])),
+            Err("ci has unknown scope deploy:prod".to_owned())
+        );
+    }
+
+    #[test]
+    fn quick_tokens_are_identified_directly() {
+        let tokens = tokens(vec![
+            (
+                toml::from_str::<NamedToken>("token = \"one\"\nscopes = []")
+                    .unwrap()
+                    .token,
+                "one",
+            ),
+            (Token::Sha256(digest("two")), "two"),
+        ]);
+        assert_eq!(tokens.identify("one").unwrap().name, "one");
+        assert_eq!(tokens.identify("two").unwrap().name, "two");
+        assert!(tokens.identify("three").is_none());
+        assert!(tokens.slow().is_empty());
+    }
+
+    #[test]
+    fn slow_tokens_are_only_identified_once_remembered() {
+        let tokens = tokens(vec![(argon2("slow"), "slow")]);
+        assert!(tokens.identify("slow").is_none());
+        let slow = tokens.slow();
+        assert_eq!(slow.len(), 1);
+        assert!(slow[0].0.matches("slow"));
+        assert!(!slow[0].0.matches("fast"));
+        tokens.remember("slow", &slow[0].1);
+        assert_eq!(tokens.identify("slow").unwrap().name, "slow");
+        assert!(tokens.identify("fast").is_none());
+    }
+
+    #[test]
+    fn identities_reloaded_away_are_not_remembered() {
+        let tokens = tokens(vec![(argon2("slow"), "slow")]);
+        let stale = Arc::new(Identity::new("slow".to_owned(), Vec::new()));
+        tokens.remember("slow", &stale);
+        assert!(tokens.identify("slow").is_none());
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::{BTreeMap, HashMap};
+
+use tokio::process::Command;
+
+use crate::{image::ImageRef, process, queue::Run, runtime::Runtime, Error, ManagedComposition};
+
+/// A compose invocation for this composition, with its files, project name,
+/// profiles and environment.
+pub fn command(runtime: Runtime, composition: &ManagedComposition) -> Command {
+    let mut command = runtime.compose();
+    command.current_dir(&composition.work);
+    for file in &composition.files {
+        command.arg("--file").arg(file);
+    }
+    if let Some(project_name) = &composition.project_name {
+        command.arg("--project-name").arg(project_name);
+    }
+    for profile in &composition.profiles {
+        command.arg("--profile").arg(profile);
+    }
+    for env_file in &composition.env_files {
+        command.arg("--env-file").arg(env_file);
+    }
+    command.envs(&composition.environment);
+    command
+}
+
+/// Run a compose subcommand to completion, explaining any failure.
+pub async fn run(
+    runtime: Runtime,
+    composition: &ManagedComposition,
+    args: &[&str],
+    run: &Run,
+) -> Result<(), Error> {
+    let mut command = command(runtime, composition);
+    command.args(args);
+    let output = process::capture(command, run.composition(), Some(run)).await?;
+    if !output.status.success() {
+        return Err(Error::PullFailed {
+            reason: runtime.parse_error(&output.stderr),
+            stdout: output.stdout,
+            stderr: output.stderr,
+        });
+    }
+    Ok(())
+}
+
+/// The resolved project, as reported by `docker compose config`.
+#[derive(serde::Deserialize)]
+pub struct Project {
+    pub name: String,
+    #[serde(default)]
+    pub services: BTreeMap<String, Service>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct Service {
+    pub image: Option<String>,
+    pub build: Option<serde_json::Value>,
+}
+
+impl Project {
+    pub async fn load(runtime: Runtime, composition: &ManagedComposition) -> Result<Self, Error> {
+        let output = command(runtime, composition)
+            .arg("config")
+            .arg("--format")
+            .arg("json")
+            // Don't outlive a restart that times out or is cancelled, nor
+            // catch a Ctrl-C meant for conductor
+            .kill_on_drop(true)
+            .process_group(0)
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(Error::ComposeConfig(
+                String::from_utf8_lossy(&output.stderr).into(),
+            ));
+        }
+        Ok(serde_json::from_slice(&output.stdout)?)
+    }
+
+    /// The hash of each service's resolved configuration, which compose also
+    /// labels the containers it creates with.
+    pub async fn config_hashes(
+        runtime: Runtime,
+        composition: &ManagedComposition,
+    ) -> Result<HashMap<String, String>, Error> {
+        let output = command(runtime, composition)
+            .args(["config", "--hash", "*"])
+            .kill_on_drop(true)
+            .process_group(0)
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(Error::ComposeConfig(
+                String::from_utf8_lossy(&output.stderr).into(),
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .map(|(service, hash)| (service.to_owned(), hash.trim().to_owned()))
+            .collect())
+    }
+
+    /// Images that come from a registry rather than being built locally.
+    pub fn pullable_images(&self) -> Vec<ImageRef> {
+        let mut images: Vec<ImageRef> = self
+            .services
+            .values()
+            .filter(|service| service.build.is_none())
+            .filter_map(|service| service.image.as_deref())
+            .map(ImageRef::parse)
+            .collect();
+        images.sort_by_key(ToString::to_string);
+        images.dedup();
+        images
+    }
+}
@@ -0,0 +1,215 @@
+use std::{collections::HashMap, path::PathBuf, process::Stdio};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+/// Registry credentials stored by `docker login`.
+pub enum Credentials {
+    Password {
+        username: String,
+        password: String,
+    },
+    /// An OAuth refresh token, traded for an access token at the registry's
+    /// token endpoint.
+    IdentityToken(String),
+}
+
+/// Look up the credentials for `registry` in `$DOCKER_CONFIG/config.json`
+/// (or `~/.docker/config.json`), asking a credential helper if one is
+/// configured for it, like the CLI does.
+pub async fn credentials(registry: &str) -> Option<Credentials> {
+    let path = config_path()?;
+    let contents = std::fs::read_to_string(path).ok()?;
+    let config: DockerConfig = serde_json::from_str(&contents).ok()?;
+    match config.source(registry)? {
+        Source::Inline(entry) => entry.credentials(),
+        Source::Helper(helper) => from_helper(helper, registry).await,
+    }
+}
+
+/// Run `docker-credential-<helper> get`, which reads a server on stdin and
+/// answers with `{"Username": ..., "Secret": ...}`.
+async fn from_helper(helper: &str, registry: &str) -> Option<Credentials> {
+    let program = format!("docker-credential-{helper}");
+    let server = match registry {
+        "docker.io" => "https://index.docker.io/v1/",
+        other => other,
+    };
+    let mut child = Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| eprintln!("Could not run {program}: {e}"))
+        .ok()?;
+    let mut stdin = child.stdin.take()?;
+    // A helper that exits without reading its input is handled below
+    let _ = stdin.write_all(server.as_bytes()).await;
+    drop(stdin);
+    let output = child.wait_with_output().await.ok()?;
+    if !output.status.success() {
+        // Helpers exit non-zero, saying so on stdout, for servers they have
+        // nothing stored for
+        let message = [&output.stdout, &output.stderr].map(|o| String::from_utf8_lossy(o));
+        let message = message.join("").trim().to_owned();
+        if !message.contains("not found") {
+            eprintln!("{program} failed for {registry}: {message}");
+        }
+        return None;
+    }
+    let found: HelperCredentials = serde_json::from_slice(&output.stdout).ok()?;
+    Some(found.into_credentials())
+}
+
+fn config_path() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("DOCKER_CONFIG") {
+        return Some(PathBuf::from(dir).join("config.json"));
+    }
+    let home = std::env::var_os("HOME")?;
+    Some(PathBuf::from(home).join(".docker").join("config.json"))
+}
+
+/// `docker login` writes keys like `https://index.docker.io/v1/` or `ghcr.io`.
+fn normalize_server(server: &str) -> &str {
+    let server = server
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    let server = server.split('/').next().unwrap_or_default();
+    match server {
+        "index.docker.io" | "registry-1.docker.io" => "docker.io",
+        other => other,
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+    #[serde(rename = "credsStore")]
+    creds_store: Option<String>,
+    #[serde(rename = "credHelpers", default)]
+    cred_helpers: HashMap<String, String>,
+}
+
+enum Source<'a> {
+    Inline(&'a AuthEntry),
+    Helper(&'a str),
+}
+
+impl DockerConfig {
+    /// Where the CLI would look: a per-registry helper, then the default
+    /// store, then the file itself.
+    fn source(&self, registry: &str) -> Option<Source<'_>> {
+        let matches = |server: &&String| normalize_server(server) == registry;
+        if let Some((_, helper)) = self.cred_helpers.iter().find(|(s, _)| matches(s)) {
+            return Some(Source::Helper(helper));
+        }
+        if let Some(store) = self.creds_store.as_deref().filter(|s| !s.is_empty()) {
+            return Some(Source::Helper(store));
+        }
+        let (_, entry) = self.auths.iter().find(|(s, _)| matches(s))?;
+        Some(Source::Inline(entry))
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct AuthEntry {
+    auth: Option<String>,
+    identitytoken: Option<String>,
+}
+
+impl AuthEntry {
+    fn credentials(&self) -> Option<Credentials> {
+        if let Some(token) = self.identitytoken.as_ref().filter(|t| !t.is_empty()) {
+            return Some(Credentials::IdentityToken(token.clone()));
+        }
+        let decoded = STANDARD.decode(self.auth.as_deref()?).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+        Some(Credentials::Password {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        })
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct HelperCredentials {
+    #[serde(rename = "Username")]
+    username: String,
+    #[serde(rename = "Secret")]
+    secret: String,
+}
+
+impl HelperCredentials {
+    /// Helpers store identity tokens under the username `<token>`.
+    fn into_credentials(self) -> Credentials {
+        if self.username == "<token>" {
+            Credentials::IdentityToken(self.secret)
+        } else {
+            Credentials::Password {
+                username: self.username,
+                password: self.secret,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(json: &str) -> DockerConfig {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn helper(source: Option<Source<'_>>) -> Option<&str> {
+        match source? {
+            Source::Helper(helper) => Some(helper),
+            Source::Inline(_) => None,
+        }
+    }
+
+    #[test]
+    fn per_registry_helpers_win_over_the_store() {
+        let config = config(
+            r#"{"credsStore": "desktop", "credHelpers": {"123.dkr.ecr.us-east-1.amazonaws.com": "ecr-login"},
+                "auths": {"ghcr.io": {"auth": "dTpw"}}}"#,
+        );
+        assert_eq!(
+            helper(config.source("123.dkr.ecr.us-east-1.amazonaws.com")),
+            Some("ecr-login")
+        );
+        assert_eq!(helper(config.source("ghcr.io")), Some("desktop"));
+    }
+
+    #[test]
+    fn inline_entries_without_helpers() {
+        let config = config(
+            r#"{"auths": {"https://index.docker.io/v1/": {"auth": "dTpw"}, "quay.io": {"identitytoken": "t"}}}"#,
+        );
+        let Some(Source::Inline(entry)) = config.source("docker.io") else {
+            panic!("expected the inline entry");
+        };
+        let Some(Credentials::Password { username, password }) = entry.credentials() else {
+            panic!("expected a password");
+        };
+        assert_eq!((username.as_str(), password.as_str()), ("u", "p"));
+        let Some(Source::Inline(entry)) = config.source("quay.io") else {
+            panic!("expected the inline entry");
+        };
+        assert!(matches!(entry.credentials(), Some(Credentials::IdentityToken(t)) if t == "t"));
+        assert!(config.source("ghcr.io").is_none());
+    }
+
+    #[test]
+    fn helpers_mark_identity_tokens() {
+        let found: HelperCredentials = serde_json::from_str(
+            r#"{"ServerURL": "ghcr.io", "Username": "<token>", "Secret": "t"}"#,
+        )
+        .unwrap();
+        assert!(matches!(found.into_credentials(), Credentials::IdentityToken(t) if t == "t"));
+    }
+}
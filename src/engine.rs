@@ -0,0 +1,350 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use axum::{
+    body::Bytes,
+    http::{Method, Request},
+};
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use http_body_util::{BodyExt, Full};
+use hyper_util::rt::TokioIo;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, UnixStream},
+};
+
+use crate::{
+    docker_config::{self, Credentials},
+    image::ImageRef,
+    Error,
+};
+
+const DEFAULT_SOCKET: &str = "/var/run/docker.sock";
+
+/// A minimal client for the Docker Engine HTTP API.
+///
+/// Requests are unversioned, so the daemon answers with its newest API version.
+pub struct Engine {
+    host: Host,
+}
+
+enum Host {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+impl Engine {
+    /// Honours `DOCKER_HOST` like the CLI does, defaulting to the local socket.
+    pub fn from_env() -> Result<Self, Error> {
+        let docker_host = std::env::var("DOCKER_HOST").ok();
+        let host = match docker_host.as_deref() {
+            None | Some("") => Host::Unix(DEFAULT_SOCKET.into()),
+            Some(host) => {
+                if let Some(path) = host.strip_prefix("unix://") {
+                    Host::Unix(path.into())
+                } else if let Some(addr) = host.strip_prefix("tcp://") {
+                    // Plain TCP only; refuse rather than skip TLS the CLI would use
+                    let tls = ["DOCKER_TLS_VERIFY", "DOCKER_CERT_PATH"]
+                        .iter()
+                        .any(|name| std::env::var_os(name).is_some_and(|v| !v.is_empty()));
+                    if tls {
+                        return Err(Error::UnsupportedDockerTls(host.to_owned()));
+                    }
+                    Host::Tcp(addr.trim_end_matches('/').to_owned())
+                } else {
+                    return Err(Error::UnsupportedDockerHost(host.to_owned()));
+                }
+            }
+        };
+        Ok(Self { host })
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        headers: &[(&str, String)],
+    ) -> Result<Bytes, Error> {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header("host", "docker");
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+        let request = request.body(Full::new(Bytes::new()))?;
+        let response = match &self.host {
+            Host::Unix(path) => send_on(UnixStream::connect(path).await?, request).await?,
+            Host::Tcp(addr) => send_on(TcpStream::connect(addr).await?, request).await?,
+        };
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        if !status.is_success() {
+            let message = serde_json::from_slice::<ErrorMessage>(&body)
+                .map(|e| e.message)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&body).into_owned());
+            return Err(Error::Engine {
+                status: status.as_u16(),
+                message,
+            });
+        }
+        Ok(body)
+    }
+
+    /// Pull an image, by digest if it's pinned to one, using any credentials
+    /// from the docker CLI's config.
+    pub async fn pull(&self, image: &ImageRef) -> Result<PullOutcome, Error> {
+        let path = format!(
+            "/images/create?fromImage={}&tag={}",
+            encode(&format!("{}/{}", image.registry, image.repository)),
+            encode(image.tag_or_digest())
+        );
+        let mut headers = Vec::new();
+        if let Some(credentials) = docker_config::credentials(&image.registry).await {
+            let auth = match credentials {
+                Credentials::Password { username, password } => serde_json::json!({
+                    "username": username,
+                    "password": password,
+                    "serveraddress": image.registry,
+                }),
+                Credentials::IdentityToken(token) => serde_json::json!({
+                    "identitytoken": token,
+                    "serveraddress": image.registry,
+                }),
+            };
+            headers.push(("x-registry-auth", URL_SAFE.encode(auth.to_string())));
+        }
+        let body = self.send(Method::POST, &path, &headers).await?;
+        // The daemon streams progress and reports failures in-band, with a 200
+        let mut updated = false;
+        for line in body.split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+            let progress: PullProgress = serde_json::from_slice(line)?;
+            if let Some(message) = progress.error {
+                return Err(Error::ImagePull {
+                    image: image.to_string(),
+                    message,
+                });
+            }
+            if progress
+                .status
+                .is_some_and(|s| s.starts_with("Status: Downloaded newer image"))
+            {
+                updated = true;
+            }
+        }
+        let id = self.inspect_image(&image.to_string()).await?.id;
+        Ok(PullOutcome {
+            image: image.clone(),
+            id,
+            updated,
+        })
+    }
+
+    pub async fn inspect_image(&self, reference: &str) -> Result<ImageInspect, Error> {
+        // References are already path-safe, and the CLI sends them unescaped too
+        let path = format!("/images/{reference}/json");
+        let body = self.send(Method::GET, &path, &[]).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Remove every image not used by a container, like `docker image prune -a`.
+    pub async fn prune_images(&self) -> Result<PruneReport, Error> {
+        let filters = serde_json::json!({ "dangling": ["false"] });
+        let path = format!("/images/prune?filters={}", encode(&filters.to_string()));
+        let body = self.send(Method::POST, &path, &[]).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Every container belonging to a compose project, running or not.
+    pub async fn project_containers(&self, project: &str) -> Result<Vec<ContainerSummary>, Error> {
+        let filters = serde_json::json!({
+            "label": [format!("com.docker.compose.project={project}")],
+        });
+        let path = format!(
+            "/containers/json?all=true&filters={}",
+            encode(&filters.to_string())
+        );
+        let body = self.send(Method::GET, &path, &[]).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    pub async fn inspect_container(&self, id: &str) -> Result<ContainerInspect, Error> {
+        let path = format!("/containers/{}/json", encode(id));
+        let body = self.send(Method::GET, &path, &[]).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Point `image` at the image with ID `id`, like `docker tag`.
+    pub async fn tag_image(&self, id: &str, image: &ImageRef) -> Result<(), Error> {
+        let path = format!(
+            "/images/{}/tag?repo={}&tag={}",
+            encode(id),
+            encode(&format!("{}/{}", image.registry, image.repository)),
+            encode(&image.tag)
+        );
+        self.send(Method::POST, &path, &[]).await?;
+        Ok(())
+    }
+
+    /// Container events for a compose project between two unix timestamps.
+    pub async fn project_events(
+        &self,
+        project: &str,
+        since: u64,
+        until: u64,
+    ) -> Result<Vec<ContainerEvent>, Error> {
+        let filters = serde_json::json!({
+            "type": ["container"],
+            "label": [format!("com.docker.compose.project={project}")],
+        });
+        let path = format!(
+            "/events?since={since}&until={until}&filters={}",
+            encode(&filters.to_string())
+        );
+        let body = self.send(Method::GET, &path, &[]).await?;
+        let events = serde_json::Deserializer::from_slice(&body)
+            .into_iter::<ContainerEvent>()
+            .collect::<Result<_, _>>()?;
+        Ok(events)
+    }
+}
+
+async fn send_on<S>(
+    stream: S,
+    request: Request<Full<Bytes>>,
+) -> Result<hyper::Response<hyper::body::Incoming>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        if let Err(source) = connection.await {
+            eprintln!("Docker engine connection error: {source:?}");
+        }
+    });
+    Ok(sender.send_request(request).await?)
+}
+
+/// Percent-encode a query or path component.
+fn encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    out
+}
+
+/// The result of pulling one image.
+pub struct PullOutcome {
+    pub image: ImageRef,
+    pub id: String,
+    pub updated: bool,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ImageInspect {
+    #[serde(rename = "Id")]
+    pub id: String,
+    /// Every `repository@sha256:...` the image was pulled as
+    #[serde(rename = "RepoDigests", default)]
+    pub repo_digests: Vec<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct PruneReport {
+    #[serde(rename = "ImagesDeleted", default)]
+    images_deleted: Option<Vec<serde_json::Value>>,
+    #[serde(rename = "SpaceReclaimed", default)]
+    pub space_reclaimed: u64,
+}
+
+impl PruneReport {
+    pub fn images_deleted(&self) -> usize {
+        self.images_deleted.as_ref().map_or(0, Vec::len)
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct ContainerSummary {
+    #[serde(rename = "Id")]
+    pub id: String,
+    #[serde(rename = "ImageID")]
+    pub image_id: String,
+    #[serde(rename = "State")]
+    pub state: String,
+    #[serde(rename = "Labels", default)]
+    pub labels: HashMap<String, String>,
+}
+
+impl ContainerSummary {
+    pub fn service(&self) -> Option<&str> {
+        self.labels
+            .get("com.docker.compose.service")
+            .map(String::as_str)
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct ContainerInspect {
+    #[serde(rename = "State")]
+    pub state: ContainerState,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ContainerState {
+    #[serde(rename = "Status")]
+    pub status: String,
+    #[serde(rename = "StartedAt")]
+    pub started_at: String,
+    #[serde(rename = "ExitCode", default)]
+    pub exit_code: i64,
+    #[serde(rename = "Health")]
+    pub health: Option<ContainerHealth>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ContainerHealth {
+    #[serde(rename = "Status")]
+    pub status: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ContainerEvent {
+    #[serde(rename = "Action")]
+    pub action: String,
+    #[serde(rename = "Actor")]
+    pub actor: Actor,
+}
+
+#[derive(serde::Deserialize)]
+pub struct Actor {
+    /// The container's ID
+    #[serde(rename = "ID", default)]
+    pub id: String,
+    #[serde(rename = "Attributes", default)]
+    pub attributes: HashMap<String, String>,
+}
+
+impl ContainerEvent {
+    pub fn service(&self) -> Option<&str> {
+        self.actor
+            .attributes
+            .get("com.docker.compose.service")
+            .map(String::as_str)
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct PullProgress {
+    status: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct ErrorMessage {
+    message: String,
+}
@@ -0,0 +1,437 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+};
+use chrono::{DateTime, Datelike, NaiveTime, SecondsFormat, TimeDelta, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{de::Error as _, Deserialize, Deserializer};
+use tokio::sync::Notify;
+
+use crate::{
+    auth::{Caller, Scope},
+    enqueue,
+    queue::{Run, Stream},
+    AppState, Error,
+};
+
+/// How often a deferred restart re-checks, in case the clock jumped.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Hours of the week restarts may happen in.
+#[derive(serde::Deserialize)]
+pub struct Window {
+    /// Days the window opens on, like `["mon", "tue"]`. Every day if empty
+    #[serde(default)]
+    days: Vec<Weekday>,
+    /// Opening time, like `02:00`
+    start: NaiveTime,
+    /// Closing time. Earlier than `start` for windows that span midnight
+    end: NaiveTime,
+    #[serde(default = "default_timezone")]
+    timezone: Tz,
+}
+
+fn default_timezone() -> Tz {
+    Tz::UTC
+}
+
+impl Window {
+    fn on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    fn contains(&self, at: DateTime<Utc>) -> bool {
+        let local = at.with_timezone(&self.timezone);
+        let (day, time) = (local.weekday(), local.time());
+        if self.start < self.end {
+            self.on(day) && self.start <= time && time < self.end
+        } else {
+            (self.on(day) && time >= self.start) || (self.on(day.pred()) && time < self.end)
+        }
+    }
+
+    /// The first time after `at` that the window opens.
+    fn next_open(&self, at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let today = at.with_timezone(&self.timezone).date_naive();
+        (0..=7)
+            .filter_map(|offset| today.checked_add_signed(TimeDelta::days(offset)))
+            .filter(|date| self.on(date.weekday()))
+            .filter_map(|date| {
+                let opens = date.and_time(self.start);
+                self.timezone.from_local_datetime(&opens).earliest()
+            })
+            .map(|opens| opens.with_timezone(&Utc))
+            .find(|opens| *opens > at)
+    }
+}
+
+/// A period no restarts may happen in.
+#[derive(Clone, serde::Deserialize)]
+pub struct Freeze {
+    #[serde(deserialize_with = "deserialize_time")]
+    start: DateTime<Utc>,
+    /// When the freeze lifts. Never, if unset
+    #[serde(default, deserialize_with = "deserialize_optional_time")]
+    end: Option<DateTime<Utc>>,
+    reason: Option<String>,
+}
+
+impl Freeze {
+    fn contains(&self, at: DateTime<Utc>) -> bool {
+        self.start <= at && self.end.is_none_or(|end| at < end)
+    }
+}
+
+/// TOML has its own datetimes, which chrono can't read, but accept strings too.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Timestamp {
+    Toml(toml::value::Datetime),
+    Text(String),
+}
+
+fn deserialize_time<'de, D: Deserializer<'de>>(de: D) -> Result<DateTime<Utc>, D::Error> {
+    let text = match Timestamp::deserialize(de)? {
+        Timestamp::Toml(datetime) => datetime.to_string(),
+        Timestamp::Text(text) => text,
+    };
+    DateTime::parse_from_rfc3339(&text)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|e| D::Error::custom(format!("`{text}` is not an RFC 3339 timestamp: {e}")))
+}
+
+fn deserialize_optional_time<'de, D: Deserializer<'de>>(
+    de: D,
+) -> Result<Option<DateTime<Utc>>, D::Error> {
+    deserialize_time(de).map(Some)
+}
+
+/// What happens to a restart requested while restarts aren't allowed.
+#[derive(Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FreezePolicy {
+    /// Refuse it with `423 Locked`
+    #[default]
+    Reject,
+    /// Hold on to it, and restart as soon as restarts are allowed again
+    Queue,
+}
+
+/// Why a restart can't happen right now, and until when.
+#[derive(Debug)]
+pub struct Blocked {
+    reason: String,
+    until: Option<DateTime<Utc>>,
+}
+
+impl Display for Blocked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.until {
+            Some(until) => write!(
+                f,
+                "{} until {}",
+                self.reason,
+                until.to_rfc3339_opts(SecondsFormat::Secs, true)
+            ),
+            None => write!(f, "{} indefinitely", self.reason),
+        }
+    }
+}
+
+/// Freezes started and ended over the API, globally (`None`) or for one
+/// composition.
+#[derive(Default)]
+pub struct Freezes {
+    active: Mutex<HashMap<Option<String>, Freeze>>,
+    changed: Notify,
+}
+
+impl Freezes {
+    fn set(&self, name: Option<String>, freeze: Option<Freeze>) {
+        let mut active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        match freeze {
+            Some(freeze) => active.insert(name, freeze),
+            None => active.remove(&name),
+        };
+        drop(active);
+        self.changed.notify_waiters();
+    }
+}
+
+/// Why `name` may not restart right now, if it may not.
+pub fn blocked(state: &AppState, name: &str) -> Option<Blocked> {
+    let composition = state.config.extra.get(name)?;
+    let windows = if composition.windows.is_empty() {
+        &state.config.windows
+    } else {
+        &composition.windows
+    };
+    let mut freezes: Vec<Freeze> = state
+        .config
+        .freezes
+        .iter()
+        .chain(&composition.freezes)
+        .cloned()
+        .collect();
+    let active = state
+        .freezes
+        .active
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    freezes.extend(active.get(&None).cloned());
+    freezes.extend(active.get(&Some(name.to_owned())).cloned());
+    drop(active);
+
+    let now = Utc::now();
+    let reason = if let Some(freeze) = freezes.iter().find(|f| f.contains(now)) {
+        match &freeze.reason {
+            Some(reason) => format!("Frozen ({reason})"),
+            None => "Frozen".to_owned(),
+        }
+    } else if !windows.is_empty() && !windows.iter().any(|w| w.contains(now)) {
+        "Outside maintenance windows".to_owned()
+    } else {
+        return None;
+    };
+
+    // Step past freezes and closed hours until both are clear
+    let mut until = now;
+    for _ in 0..64 {
+        if let Some(freeze) = freezes.iter().find(|f| f.contains(until)) {
+            match freeze.end {
+                Some(end) => until = end,
+                None => {
+                    return Some(Blocked {
+                        reason,
+                        until: None,
+                    })
+                }
+            }
+        } else if !windows.is_empty() && !windows.iter().any(|w| w.contains(until)) {
+            match windows.iter().filter_map(|w| w.next_open(until)).min() {
+                Some(opens) => until = opens,
+                None => {
+                    return Some(Blocked {
+                        reason,
+                        until: None,
+                    })
+                }
+            }
+        } else {
+            break;
+        }
+    }
+    Some(Blocked {
+        reason,
+        until: Some(until),
+    })
+}
+
+pub fn policy(state: &AppState, name: &str) -> FreezePolicy {
+    state
+        .config
+        .extra
+        .get(name)
+        .and_then(|composition| composition.freeze_policy)
+        .unwrap_or(state.config.freeze_policy)
+}
+
+/// Hold a restart back for as long as it isn't allowed to happen.
+pub async fn wait_open(name: &str, state: &AppState, run: &Run) {
+    loop {
+        let changed = state.freezes.changed.notified();
+        let Some(blocked) = blocked(state, name) else {
+            return;
+        };
+        let message = format!("Deferred: {blocked}");
+        println!("[{name}] {message}");
+        run.push_line(Stream::Stderr, message);
+        let wait = blocked
+            .until
+            .and_then(|until| (until - Utc::now()).to_std().ok())
+            .map_or(RECHECK_INTERVAL, |wait| wait.min(RECHECK_INTERVAL));
+        tokio::select! {
+            () = changed => {}
+            () = tokio::time::sleep(wait) => {}
+        }
+    }
+}
+
+#[derive(Default, serde::Deserialize)]
+pub struct FreezeRequest {
+    #[serde(default, deserialize_with = "deserialize_optional_time")]
+    until: Option<DateTime<Utc>>,
+    reason: Option<String>,
+}
+
+impl FreezeRequest {
+    /// No body at all freezes indefinitely, but a body that doesn't parse is
+    /// an error rather than the same thing.
+    fn parse(body: &[u8]) -> Result<Self, Error> {
+        if body.iter().all(u8::is_ascii_whitespace) {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_slice(body)?)
+    }
+
+    fn into_freeze(self) -> Freeze {
+        Freeze {
+            start: Utc::now(),
+            end: self.until,
+            reason: self.reason,
+        }
+    }
+}
+
+/// Freezes every composition, optionally `until` some time.
+pub async fn freeze_all_web(
+    State(state): State<Arc<AppState>>,
+    Caller(identity): Caller,
+    body: Bytes,
+) -> Result<&'static str, Error> {
+    identity.check(Scope::Freeze)?;
+    let request = FreezeRequest::parse(&body)?;
+    state.freezes.set(None, Some(request.into_freeze()));
+    Ok("Frozen\n")
+}
+
+/// Lifts a freeze started with `freeze_all_web`.
+pub async fn thaw_all_web(
+    State(state): State<Arc<AppState>>,
+    Caller(identity): Caller,
+) -> Result<&'static str, Error> {
+    identity.check(Scope::Freeze)?;
+    state.freezes.set(None, None);
+    Ok("Thawed\n")
+}
+
+/// Freezes one composition, optionally `until` some time.
+pub async fn freeze_web(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Caller(identity): Caller,
+    body: Bytes,
+) -> Result<&'static str, Error> {
+    identity.check(Scope::Freeze)?;
+    if !state.config.extra.contains_key(&name) {
+        return Err(Error::NoComposition(name));
+    }
+    let request = FreezeRequest::parse(&body)?;
+    state.freezes.set(Some(name), Some(request.into_freeze()));
+    Ok("Frozen\n")
+}
+
+/// Lifts a freeze started with `freeze_web`.
+pub async fn thaw_web(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Caller(identity): Caller,
+) -> Result<&'static str, Error> {
+    identity.check(Scope::Freeze)?;
+    if !state.config.extra.contains_key(&name) {
+        return Err(Error::NoComposition(name));
+    }
+    state.freezes.set(Some(name), None);
+    Ok("Thawed\n")
+}
+
+/// Queue a restart that isn't allowed to happen yet, without waiting for it.
+/// Returns `None`, queueing nothing, if it's allowed right away.
+pub fn defer(name: &str, state: &Arc<AppState>) -> Result<Option<Blocked>, Error> {
+    let Some(blocked) = blocked(state, name) else {
+        return Ok(None);
+    };
+    enqueue(name, None, state)?;
+    Ok(Some(blocked))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().into()
+    }
+
+    fn window(days: &[Weekday], start: &str, end: &str) -> Window {
+        Window {
+            days: days.to_vec(),
+            start: start.parse().unwrap(),
+            end: end.parse().unwrap(),
+            timezone: Tz::UTC,
+        }
+    }
+
+    #[test]
+    fn window_within_a_day() {
+        let window = window(&[Weekday::Mon], "02:00:00", "04:00:00");
+        // 2026-01-05 is a Monday
+        assert!(window.contains(at("2026-01-05T02:00:00Z")));
+        assert!(window.contains(at("2026-01-05T03:59:59Z")));
+        assert!(!window.contains(at("2026-01-05T04:00:00Z")));
+        assert!(!window.contains(at("2026-01-06T03:00:00Z")));
+    }
+
+    #[test]
+    fn window_spanning_midnight_belongs_to_the_day_it_opens() {
+        let window = window(&[Weekday::Mon], "22:00:00", "06:00:00");
+        assert!(window.contains(at("2026-01-05T23:00:00Z")));
+        assert!(window.contains(at("2026-01-06T05:00:00Z")));
+        assert!(!window.contains(at("2026-01-05T05:00:00Z")));
+        assert!(!window.contains(at("2026-01-06T23:00:00Z")));
+    }
+
+    #[test]
+    fn window_in_another_timezone() {
+        let mut window = window(&[], "02:00:00", "04:00:00");
+        window.timezone = chrono_tz::America::New_York;
+        assert!(window.contains(at("2026-01-05T08:00:00Z")));
+        assert!(!window.contains(at("2026-01-05T03:00:00Z")));
+    }
+
+    #[test]
+    fn next_open_skips_other_days() {
+        let window = window(&[Weekday::Wed], "02:00:00", "04:00:00");
+        assert_eq!(
+            window.next_open(at("2026-01-05T12:00:00Z")),
+            Some(at("2026-01-07T02:00:00Z"))
+        );
+        // Already open today counts from next week
+        assert_eq!(
+            window.next_open(at("2026-01-07T03:00:00Z")),
+            Some(at("2026-01-14T02:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn empty_freeze_request_freezes_indefinitely() {
+        let request = FreezeRequest::parse(b"").unwrap();
+        assert!(request.until.is_none());
+        assert!(FreezeRequest::parse(b"  \n").unwrap().until.is_none());
+    }
+
+    #[test]
+    fn freeze_request_until() {
+        let request = FreezeRequest::parse(br#"{"until":"2026-01-05T02:00:00Z"}"#).unwrap();
+        assert_eq!(request.until, Some(at("2026-01-05T02:00:00Z")));
+    }
+
+    #[test]
+    fn malformed_freeze_requests_are_rejected() {
+        for body in [
+            &br#"{"until":"garbage"}"#[..],
+            b"{",
+            b"[]",
+            br#"{"until":5}"#,
+        ] {
+            assert!(matches!(FreezeRequest::parse(body), Err(Error::Payload(_))));
+        }
+    }
+}
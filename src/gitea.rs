@@ -0,0 +1,250 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::HeaderMap,
+    response::Response,
+};
+
+use crate::{
+    webhook::{accept_if, header, verify_hmac_sha256, WebhookTrigger},
+    AppState, Error,
+};
+
+/// Receives Gitea webhooks for a single composition, signed with `gitea.secret`.
+pub async fn gitea_web(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, Error> {
+    let Some(composition) = state.config.extra.get(&name) else {
+        return Err(Error::NoComposition(name));
+    };
+    let Some(trigger) = &composition.gitea else {
+        return Err(Error::NoWebhook(name));
+    };
+    let signature = header(&headers, "x-gitea-signature");
+    let event = header(&headers, "x-gitea-event");
+    let matched = check(trigger, signature, event, &body)?;
+    accept_if(matched, &name, &state)
+}
+
+/// Receives Forgejo webhooks for a single composition, signed with `forgejo.secret`.
+///
+/// Forgejo still sends the `X-Gitea-*` headers, but prefers its own names.
+pub async fn forgejo_web(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, Error> {
+    let Some(composition) = state.config.extra.get(&name) else {
+        return Err(Error::NoComposition(name));
+    };
+    let Some(trigger) = &composition.forgejo else {
+        return Err(Error::NoWebhook(name));
+    };
+    let signature =
+        header(&headers, "x-forgejo-signature").or_else(|| header(&headers, "x-gitea-signature"));
+    let event = header(&headers, "x-forgejo-event").or_else(|| header(&headers, "x-gitea-event"));
+    let matched = check(trigger, signature, event, &body)?;
+    accept_if(matched, &name, &state)
+}
+
+/// Verify the signature and decide whether the event should redeploy.
+/// `push`, `package` and successful `workflow_run` events are understood.
+fn check(
+    trigger: &WebhookTrigger,
+    signature: Option<&str>,
+    event: Option<&str>,
+    body: &[u8],
+) -> Result<bool, Error> {
+    let signature = signature.ok_or(Error::InvalidSignature)?;
+    verify_hmac_sha256(trigger.secret(), body, signature)?;
+
+    let matched = match event.unwrap_or_default() {
+        "push" => {
+            let event: PushEvent = serde_json::from_slice(body)?;
+            event
+                .git_ref
+                .strip_prefix("refs/heads/")
+                .is_some_and(|branch| trigger.matches_branch(branch))
+        }
+        "package" => {
+            let event: PackageEvent = serde_json::from_slice(body)?;
+            let package = event.package;
+            let full_name = format!("{}/{}", package.owner.login, package.name);
+            let host = package
+                .html_url
+                .as_deref()
+                .and_then(|url| url.split_once("://"))
+                .and_then(|(_, rest)| rest.split('/').next());
+            let hosted_name = host.map(|host| format!("{host}/{full_name}"));
+            let mut candidates = vec![package.name.as_str(), &full_name];
+            candidates.extend(hosted_name.as_deref());
+            event.action == "created"
+                && package.package_type == "container"
+                && trigger.matches_image(&candidates, Some(&package.version))
+        }
+        "workflow_run" => {
+            let event: WorkflowRunEvent = serde_json::from_slice(body)?;
+            let run = event.workflow_run;
+            event.action == "completed"
+                && run.conclusion.as_deref() == Some("success")
+                && trigger.matches_workflow(&run.name, run.head_branch.as_deref())
+        }
+        _ => false,
+    };
+    Ok(matched)
+}
+
+#[derive(serde::Deserialize)]
+struct PushEvent {
+    #[serde(rename = "ref")]
+    git_ref: String,
+}
+
+#[derive(serde::Deserialize)]
+struct PackageEvent {
+    action: String,
+    package: Package,
+}
+
+#[derive(serde::Deserialize)]
+struct Package {
+    name: String,
+    owner: Owner,
+    #[serde(rename = "type")]
+    package_type: String,
+    version: String,
+    html_url: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct Owner {
+    login: String,
+}
+
+#[derive(serde::Deserialize)]
+struct WorkflowRunEvent {
+    action: String,
+    workflow_run: WorkflowRun,
+}
+
+#[derive(serde::Deserialize)]
+struct WorkflowRun {
+    name: String,
+    head_branch: Option<String>,
+    conclusion: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use super::*;
+
+    const SECRET: &str = "gitea secret";
+
+    fn trigger() -> WebhookTrigger {
+        toml::from_str(&format!(
+            r#"secret = "{SECRET}"
+branches = ["main"]
+workflows = ["CI"]
+images = ["git.example.com/me/app:latest"]"#
+        ))
+        .unwrap()
+    }
+
+    fn sign(body: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(body.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn check_signed(event: &str, body: &str) -> bool {
+        check(&trigger(), Some(&sign(body)), Some(event), body.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn signatures_are_checked() {
+        let body = r#"{"ref": "refs/heads/main"}"#;
+        let trigger = trigger();
+        assert!(check(&trigger, Some(&sign(body)), Some("push"), body.as_bytes()).unwrap());
+        let tampered = br#"{"ref": "refs/heads/mainx"}"#;
+        for (signature, body) in [
+            (None, body.as_bytes()),
+            (Some(sign(body)), tampered.as_slice()),
+            (Some("00".repeat(32)), body.as_bytes()),
+        ] {
+            assert!(matches!(
+                check(&trigger, signature.as_deref(), Some("push"), body),
+                Err(Error::InvalidSignature)
+            ));
+        }
+    }
+
+    #[test]
+    fn pushes_match_on_branch() {
+        assert!(check_signed("push", r#"{"ref": "refs/heads/main"}"#));
+        assert!(!check_signed("push", r#"{"ref": "refs/heads/dev"}"#));
+        assert!(!check_signed("create", r#"{"ref": "refs/heads/main"}"#));
+    }
+
+    #[test]
+    fn packages_match_on_image() {
+        // Forgejo and Gitea send the same package payload
+        let package = |action: &str, kind: &str, version: &str| {
+            format!(
+                r#"{{"action": "{action}", "package": {{"name": "app", "owner": {{"login": "me"}},
+                    "type": "{kind}", "version": "{version}",
+                    "html_url": "https://git.example.com/me/-/packages/container/app/{version}"}}}}"#
+            )
+        };
+        assert!(check_signed(
+            "package",
+            &package("created", "container", "latest")
+        ));
+        assert!(!check_signed(
+            "package",
+            &package("created", "container", "v2")
+        ));
+        assert!(!check_signed(
+            "package",
+            &package("deleted", "container", "latest")
+        ));
+        assert!(!check_signed(
+            "package",
+            &package("created", "npm", "latest")
+        ));
+    }
+
+    #[test]
+    fn workflow_runs_match_on_success() {
+        let run = |action: &str, name: &str, conclusion: &str| {
+            format!(
+                r#"{{"action": "{action}", "workflow_run": {{"name": "{name}", "head_branch": "main",
+                    "conclusion": "{conclusion}"}}}}"#
+            )
+        };
+        assert!(check_signed(
+            "workflow_run",
+            &run("completed", "CI", "success")
+        ));
+        assert!(!check_signed(
+            "workflow_run",
+            &run("completed", "CI", "failure")
+        ));
+        assert!(!check_signed(
+            "workflow_run",
+            &run("requested", "CI", "success")
+        ));
+        assert!(!check_signed(
+            "workflow_run",
+            &run("completed", "Lint", "success")
+        ));
+    }
+}
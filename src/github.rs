@@ -0,0 +1,115 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    webhook::{accept_if, header, verify_hmac_sha256},
+    AppState, Error,
+};
+
+/// Receives GitHub (and GHCR package) webhooks for a single composition.
+///
+/// The payload must be signed with the composition's `github.secret`, and only
+/// `push`, `package` and `workflow_run` events matching the trigger redeploy.
+pub async fn github_web(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, Error> {
+    let Some(composition) = state.config.extra.get(&name) else {
+        return Err(Error::NoComposition(name));
+    };
+    let Some(trigger) = &composition.github else {
+        return Err(Error::NoWebhook(name));
+    };
+    let signature = header(&headers, "x-hub-signature-256")
+        .and_then(|v| v.strip_prefix("sha256="))
+        .ok_or(Error::InvalidSignature)?;
+    verify_hmac_sha256(trigger.secret(), &body, signature)?;
+
+    let matched = match header(&headers, "x-github-event").unwrap_or_default() {
+        "ping" => return Ok((StatusCode::OK, "Pong\n").into_response()),
+        "push" => {
+            let event: PushEvent = serde_json::from_slice(&body)?;
+            event
+                .git_ref
+                .strip_prefix("refs/heads/")
+                .is_some_and(|branch| trigger.matches_branch(branch))
+        }
+        "package" => {
+            let event: PackageEvent = serde_json::from_slice(&body)?;
+            let package = event.package;
+            let full_name = format!("{}/{}", package.namespace, package.name);
+            let ghcr_name = format!("ghcr.io/{full_name}");
+            let tag = package
+                .package_version
+                .as_ref()
+                .and_then(|v| v.container_metadata.as_ref())
+                .and_then(|m| m.tag.name.as_deref());
+            let candidates = [package.name.as_str(), &full_name, &ghcr_name];
+            event.action != "deleted" && trigger.matches_image(&candidates, tag)
+        }
+        "workflow_run" => {
+            let event: WorkflowRunEvent = serde_json::from_slice(&body)?;
+            let run = event.workflow_run;
+            event.action == "completed"
+                && run.conclusion.as_deref() == Some("success")
+                && trigger.matches_workflow(&run.name, run.head_branch.as_deref())
+        }
+        _ => false,
+    };
+    accept_if(matched, &name, &state)
+}
+
+#[derive(serde::Deserialize)]
+struct PushEvent {
+    #[serde(rename = "ref")]
+    git_ref: String,
+}
+
+#[derive(serde::Deserialize)]
+struct PackageEvent {
+    action: String,
+    package: Package,
+}
+
+#[derive(serde::Deserialize)]
+struct Package {
+    name: String,
+    namespace: String,
+    package_version: Option<PackageVersion>,
+}
+
+#[derive(serde::Deserialize)]
+struct PackageVersion {
+    container_metadata: Option<ContainerMetadata>,
+}
+
+#[derive(serde::Deserialize)]
+struct ContainerMetadata {
+    tag: ContainerTag,
+}
+
+#[derive(serde::Deserialize)]
+struct ContainerTag {
+    name: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct WorkflowRunEvent {
+    action: String,
+    workflow_run: WorkflowRun,
+}
+
+#[derive(serde::Deserialize)]
+struct WorkflowRun {
+    name: String,
+    head_branch: Option<String>,
+    conclusion: Option<String>,
+}
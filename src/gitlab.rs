@@ -0,0 +1,153 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::HeaderMap,
+    response::Response,
+};
+
+use crate::{
+    webhook::{accept_if, header, verify_token, WebhookTrigger},
+    AppState, Error,
+};
+
+/// Receives GitLab webhooks for a single composition.
+///
+/// GitLab doesn't sign payloads, so `gitlab.secret` is compared against the
+/// `X-Gitlab-Token` header. `Push Hook` events match on branch, and successful
+/// `Pipeline Hook` events match on the pipeline's name (`workflow:name`).
+pub async fn gitlab_web(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, Error> {
+    let Some(composition) = state.config.extra.get(&name) else {
+        return Err(Error::NoComposition(name));
+    };
+    let Some(trigger) = &composition.gitlab else {
+        return Err(Error::NoWebhook(name));
+    };
+    let token = header(&headers, "x-gitlab-token");
+    let event = header(&headers, "x-gitlab-event");
+    let matched = check(trigger, token, event, &body)?;
+    accept_if(matched, &name, &state)
+}
+
+/// Verify the token and decide whether the event should redeploy.
+fn check(
+    trigger: &WebhookTrigger,
+    token: Option<&str>,
+    event: Option<&str>,
+    body: &[u8],
+) -> Result<bool, Error> {
+    let token = token.ok_or(Error::InvalidSignature)?;
+    verify_token(trigger.secret(), token)?;
+
+    let matched = match event.unwrap_or_default() {
+        "Push Hook" => {
+            let event: PushEvent = serde_json::from_slice(body)?;
+            event
+                .git_ref
+                .strip_prefix("refs/heads/")
+                .is_some_and(|branch| trigger.matches_branch(branch))
+        }
+        "Pipeline Hook" => {
+            let event: PipelineEvent = serde_json::from_slice(body)?;
+            let pipeline = event.object_attributes;
+            pipeline.status == "success"
+                && pipeline.name.is_some_and(|pipeline_name| {
+                    trigger.matches_workflow(&pipeline_name, Some(&pipeline.git_ref))
+                })
+        }
+        _ => false,
+    };
+    Ok(matched)
+}
+
+#[derive(serde::Deserialize)]
+struct PushEvent {
+    #[serde(rename = "ref")]
+    git_ref: String,
+}
+
+#[derive(serde::Deserialize)]
+struct PipelineEvent {
+    object_attributes: Pipeline,
+}
+
+#[derive(serde::Deserialize)]
+struct Pipeline {
+    name: Option<String>,
+    #[serde(rename = "ref")]
+    git_ref: String,
+    status: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trigger() -> WebhookTrigger {
+        toml::from_str(
+            r#"secret = "gitlab secret"
+branches = ["main"]
+workflows = ["Release"]"#,
+        )
+        .unwrap()
+    }
+
+    const PUSH: &[u8] = br#"{"object_kind": "push", "ref": "refs/heads/main"}"#;
+
+    #[test]
+    fn tokens_are_checked() {
+        let trigger = trigger();
+        let push = Some("Push Hook");
+        assert!(check(&trigger, Some("gitlab secret"), push, PUSH).unwrap());
+        for token in [None, Some(""), Some("gitlab secre"), Some("gitlab secret ")] {
+            assert!(matches!(
+                check(&trigger, token, push, PUSH),
+                Err(Error::InvalidSignature)
+            ));
+        }
+    }
+
+    #[test]
+    fn events_are_matched() {
+        let trigger = trigger();
+        let check = |event, body: &str| {
+            check(
+                &trigger,
+                Some("gitlab secret"),
+                Some(event),
+                body.as_bytes(),
+            )
+            .unwrap()
+        };
+        assert!(!check("Push Hook", r#"{"ref": "refs/heads/dev"}"#));
+        assert!(!check("Push Hook", r#"{"ref": "refs/tags/main"}"#));
+        let pipeline = |name: &str, git_ref: &str, status: &str| {
+            format!(
+                r#"{{"object_attributes": {{"name": "{name}", "ref": "{git_ref}", "status": "{status}"}}}}"#
+            )
+        };
+        assert!(check(
+            "Pipeline Hook",
+            &pipeline("Release", "main", "success")
+        ));
+        assert!(!check(
+            "Pipeline Hook",
+            &pipeline("Release", "main", "failed")
+        ));
+        assert!(!check(
+            "Pipeline Hook",
+            &pipeline("Release", "dev", "success")
+        ));
+        assert!(!check(
+            "Pipeline Hook",
+            &pipeline("Lint", "main", "success")
+        ));
+        assert!(!check("Tag Push Hook", r#"{"ref": "refs/heads/main"}"#));
+    }
+}
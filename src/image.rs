@@ -0,0 +1,194 @@
+use std::fmt::Display;
+
+const DEFAULT_REGISTRY: &str = "docker.io";
+const DEFAULT_TAG: &str = "latest";
+
+/// A fully-qualified image reference, like `docker.io/library/nginx:latest`.
+///
+/// Parsing follows the docker CLI's rules, so `nginx` and
+/// `docker.io/library/nginx:latest` are the same image.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ImageRef {
+    pub registry: String,
+    pub repository: String,
+    pub tag: String,
+    /// Like `sha256:...`, for images pinned with `@`, which is what gets
+    /// pulled then, whatever the tag
+    pub digest: Option<String>,
+}
+
+impl ImageRef {
+    pub fn parse(reference: &str) -> Self {
+        let (reference, digest) = match reference.split_once('@') {
+            Some((reference, digest)) => (reference, Some(digest.to_owned())),
+            None => (reference, None),
+        };
+        let (name, tag) = match reference.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => (name, tag),
+            _ => (reference, DEFAULT_TAG),
+        };
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, rest))
+                if host.contains('.') || host.contains(':') || host == "localhost" =>
+            {
+                (host, rest.to_owned())
+            }
+            _ => (DEFAULT_REGISTRY, name.to_owned()),
+        };
+        let registry = match registry {
+            "index.docker.io" | "registry-1.docker.io" => DEFAULT_REGISTRY,
+            other => other,
+        };
+        let repository = if registry == DEFAULT_REGISTRY && !repository.contains('/') {
+            format!("library/{repository}")
+        } else {
+            repository
+        };
+        Self {
+            registry: registry.to_ascii_lowercase(),
+            repository,
+            tag: tag.to_owned(),
+            digest,
+        }
+    }
+
+    /// Build a reference from the parts a registry reports separately.
+    pub fn from_parts(registry: Option<&str>, repository: &str, tag: &str) -> Self {
+        match registry {
+            Some(registry) => Self::parse(&format!("{registry}/{repository}:{tag}")),
+            None => Self::parse(&format!("{repository}:{tag}")),
+        }
+    }
+
+    /// The tag or digest to pull, as the engine's `tag` parameter takes either.
+    pub fn tag_or_digest(&self) -> &str {
+        self.digest.as_deref().unwrap_or(&self.tag)
+    }
+
+    /// Whether this image is matched by `pattern`, which is an image reference
+    /// that may contain `*` wildcards, like `ghcr.io/me/*:v1.*`. Digests
+    /// aren't matched on.
+    pub fn matches(&self, pattern: &str) -> bool {
+        let pattern = Self::parse(pattern);
+        glob(&pattern.registry, &self.registry)
+            && glob(&pattern.repository, &self.repository)
+            && glob(&pattern.tag, &self.tag)
+    }
+}
+
+impl Display for ImageRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.digest {
+            Some(digest) => write!(f, "{}/{}@{digest}", self.registry, self.repository),
+            None => write!(f, "{}/{}:{}", self.registry, self.repository, self.tag),
+        }
+    }
+}
+
+/// Match `text` against `pattern`, where `*` matches any run of characters.
+pub fn glob(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.as_bytes(), text.as_bytes());
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn references_are_normalized() {
+        let nginx = ImageRef::parse("nginx");
+        assert_eq!(nginx.to_string(), "docker.io/library/nginx:latest");
+        assert_eq!(nginx, ImageRef::parse("docker.io/library/nginx:latest"));
+        assert_eq!(nginx, ImageRef::parse("index.docker.io/library/nginx"));
+        assert_eq!(
+            ImageRef::parse("me/app:v2").to_string(),
+            "docker.io/me/app:v2"
+        );
+        assert_eq!(
+            ImageRef::parse("GHCR.io/me/app:v2").to_string(),
+            "ghcr.io/me/app:v2"
+        );
+        assert_eq!(
+            ImageRef::from_parts(Some("localhost"), "app", "1.4").to_string(),
+            "localhost/app:1.4"
+        );
+    }
+
+    #[test]
+    fn registry_ports_are_not_tags() {
+        let app = ImageRef::parse("localhost:5000/team/app");
+        assert_eq!(app.registry, "localhost:5000");
+        assert_eq!(app.repository, "team/app");
+        assert_eq!(app.tag, "latest");
+        assert_eq!(ImageRef::parse("registry:5000/app:1.4").tag, "1.4");
+    }
+
+    #[test]
+    fn patterns_match_parts_separately() {
+        let app = ImageRef::parse("ghcr.io/me/app:v1.4");
+        assert!(app.matches("ghcr.io/me/*:v1.*"));
+        assert!(app.matches("ghcr.io/*:*"));
+        // Like docker, leaving the tag out means `latest`
+        assert!(!app.matches("ghcr.io/me/app"));
+        assert!(!app.matches("ghcr.io/me/*:v2.*"));
+        assert!(!app.matches("docker.io/me/app:v1.4"));
+        assert!(ImageRef::parse("nginx:1.27").matches("nginx:*"));
+    }
+
+    #[test]
+    fn globs_match_any_run_of_characters() {
+        assert!(glob("*", ""));
+        assert!(glob("runner-*", "runner-1"));
+        assert!(glob("*.ci.example.com", "runner-1.ci.example.com"));
+        assert!(glob("a*b*c", "aXXbYYbc"));
+        assert!(glob("**", "anything"));
+        assert!(!glob("runner-*", "runner"));
+        assert!(!glob("*.example.com", "example.com"));
+        assert!(!glob("a*c", "abcd"));
+        assert!(!glob("", "a"));
+    }
+
+    #[test]
+    fn digests_are_kept() {
+        let digest = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let pinned = ImageRef::parse(&format!("nginx@{digest}"));
+        assert_eq!(pinned.repository, "library/nginx");
+        assert_eq!(pinned.tag, "latest");
+        assert_eq!(pinned.digest.as_deref(), Some(digest));
+        assert_eq!(pinned.tag_or_digest(), digest);
+        assert_eq!(
+            pinned.to_string(),
+            format!("docker.io/library/nginx@{digest}")
+        );
+
+        let tagged = ImageRef::parse(&format!("localhost:5000/app:1.4@{digest}"));
+        assert_eq!(tagged.registry, "localhost:5000");
+        assert_eq!(tagged.repository, "app");
+        assert_eq!(tagged.tag, "1.4");
+        assert_eq!(tagged.tag_or_digest(), digest);
+        assert!(tagged.matches("localhost:5000/app:1.*"));
+
+        let plain = ImageRef::parse("nginx:1.27");
+        assert_eq!(plain.digest, None);
+        assert_eq!(plain.tag_or_digest(), "1.27");
+        assert_ne!(plain, ImageRef::parse(&format!("nginx:1.27@{digest}")));
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+
+use crate::{
+    auth::{Caller, Scope},
+    queue::{Run, Status, Stream},
+    AppState, Error,
+};
+
+/// How many finished and unfinished jobs to remember.
+const MAX_JOBS: usize = 256;
+
+/// Restarts requested asynchronously, so their results can be fetched later.
+#[derive(Default)]
+pub struct Jobs {
+    next_id: AtomicU64,
+    jobs: Mutex<BTreeMap<u64, Arc<Job>>>,
+}
+
+pub struct Job {
+    pub id: u64,
+    pub composition: String,
+    pub run: Arc<Run>,
+}
+
+impl Jobs {
+    pub fn create(&self, composition: &str, run: Arc<Run>) -> Arc<Job> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let job = Arc::new(Job {
+            id,
+            composition: composition.to_owned(),
+            run,
+        });
+        let mut jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        jobs.insert(id, job.clone());
+        while jobs.len() > MAX_JOBS {
+            jobs.pop_first();
+        }
+        job
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<Job>> {
+        let jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        jobs.get(&id).cloned()
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct JobReport {
+    id: u64,
+    composition: String,
+    status: Status,
+    exit_code: Option<i32>,
+    duration_secs: Option<f64>,
+    stdout: String,
+    stderr: String,
+    result: Option<String>,
+    error: Option<String>,
+}
+
+impl From<&Job> for JobReport {
+    fn from(job: &Job) -> Self {
+        let progress = job.run.progress();
+        let duration_secs = progress.started.map(|started| {
+            let end = progress.finished.unwrap_or_else(Instant::now);
+            end.duration_since(started).as_secs_f64()
+        });
+        let (mut stdout, mut stderr) = (String::new(), String::new());
+        for line in &progress.output {
+            let output = match line.stream {
+                Stream::Stdout => &mut stdout,
+                Stream::Stderr => &mut stderr,
+            };
+            output.push_str(&line.text);
+            output.push('\n');
+        }
+        let (result, error) = match job.run.outcome() {
+            Some(Ok(deployment)) => (Some(deployment.to_string()), None),
+            Some(Err(source)) => (None, Some(source.to_string())),
+            None => (None, None),
+        };
+        Self {
+            id: job.id,
+            composition: job.composition.clone(),
+            status: progress.status,
+            exit_code: progress.exit_code,
+            duration_secs,
+            stdout,
+            stderr,
+            result,
+            error,
+        }
+    }
+}
+
+pub async fn job_web(
+    Path(id): Path<u64>,
+    State(state): State<Arc<AppState>>,
+    Caller(identity): Caller,
+) -> Result<Json<JobReport>, Error> {
+    let job = state.jobs.get(id).ok_or(Error::NoJob(id))?;
+    identity.check(Scope::Status(&job.composition))?;
+    Ok(Json(JobReport::from(job.as_ref())))
+}
+
+/// Cancels a job's run, killing whatever it is running. Every request merged
+/// into the same run sees it fail as cancelled.
+pub async fn cancel_job_web(
+    Path(id): Path<u64>,
+    State(state): State<Arc<AppState>>,
+    Caller(identity): Caller,
+) -> Result<(StatusCode, &'static str), Error> {
+    let job = state.jobs.get(id).ok_or(Error::NoJob(id))?;
+    identity.check(Scope::Restart(&job.composition))?;
+    if !job.run.cancel() {
+        return Err(Error::JobFinished(id));
+    }
+    Ok((StatusCode::ACCEPTED, "Cancelling\n"))
+}
@@ -0,0 +1,277 @@
+use std::{
+    fs::Permissions,
+    io,
+    net::SocketAddr,
+    ops::Range,
+    os::{
+        fd::{FromRawFd, RawFd},
+        unix::fs::{FileTypeExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{body::Body, http::Request, Router};
+use hyper::{body::Incoming, server::conn::http1, service::service_fn};
+use hyper_util::{
+    rt::TokioIo,
+    server::graceful::{GracefulShutdown, Watcher},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UnixListener},
+    task::JoinSet,
+};
+use tower_service::Service;
+
+use crate::{auth::ClientIdentity, tls::Tls, Config};
+
+/// The first descriptor systemd passes with socket activation.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Somewhere to listen: an address like `127.0.0.1:8080` or `[::1]:8080`, or
+/// a unix socket like `{ path = "/run/conductor/conductor.sock", mode = 0o660 }`.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix { path: PathBuf, mode: Option<u32> },
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    /// With the path to remove on shutdown, if we made the socket
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+impl Listener {
+    /// Where clients can reach this, like `https://127.0.0.1:8443`.
+    pub fn describe(&self, tls: bool) -> String {
+        match self {
+            Self::Tcp(tcp) => {
+                let scheme = if tls { "https" } else { "http" };
+                match tcp.local_addr() {
+                    Ok(address) => format!("{scheme}://{address}"),
+                    Err(source) => format!("{scheme} on an unknown address ({source})"),
+                }
+            }
+            Self::Unix(unix, _) => match unix.local_addr() {
+                Ok(address) => match address.as_pathname() {
+                    Some(path) => format!("unix:{}", path.display()),
+                    None => "an unnamed unix socket".to_owned(),
+                },
+                Err(source) => format!("a unix socket ({source})"),
+            },
+        }
+    }
+}
+
+/// The sockets systemd passed by socket activation, not adopted yet.
+pub struct Inherited(Range<RawFd>);
+
+impl Inherited {
+    /// Take the sockets in `$LISTEN_FDS`, if they're meant for this process,
+    /// clearing the variables so compose and the rest of our children don't
+    /// think they're theirs.
+    ///
+    /// Call this before any threads start, since changing the environment
+    /// while other threads might read it is a data race.
+    pub fn take() -> Self {
+        let ours = std::env::var("LISTEN_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            == Some(std::process::id());
+        let count: RawFd = std::env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(0);
+        for variable in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            std::env::remove_var(variable);
+        }
+        let count = if ours { count.max(0) } else { 0 };
+        Self(SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+    }
+}
+
+/// The addresses of the TCP listeners among `listeners`.
+pub fn tcp_addresses(listeners: &[Listener]) -> Vec<SocketAddr> {
+    listeners
+        .iter()
+        .filter_map(|listener| match listener {
+            Listener::Tcp(tcp) => tcp.local_addr().ok(),
+            Listener::Unix(..) => None,
+        })
+        .collect()
+}
+
+/// The sockets systemd passed if it started us by socket activation,
+/// otherwise the ones `listen` asks for, otherwise every IPv4 address on
+/// `port`.
+pub async fn bind(config: &Config, inherited: Inherited) -> Result<Vec<Listener>, String> {
+    if !inherited.0.is_empty() {
+        return inherited.0.map(adopt).collect();
+    }
+    if config.listen.is_empty() {
+        let address = SocketAddr::from(([0, 0, 0, 0], config.port));
+        return Ok(vec![bind_tcp(address).await?]);
+    }
+    let mut listeners = Vec::new();
+    for listen in &config.listen {
+        listeners.push(match listen {
+            Listen::Tcp(address) => bind_tcp(*address).await?,
+            Listen::Unix { path, mode } => bind_unix(path, *mode)?,
+        });
+    }
+    Ok(listeners)
+}
+
+async fn bind_tcp(address: SocketAddr) -> Result<Listener, String> {
+    TcpListener::bind(address)
+        .await
+        .map(Listener::Tcp)
+        .map_err(|e| format!("{address}: {e}"))
+}
+
+fn bind_unix(path: &Path, mode: Option<u32>) -> Result<Listener, String> {
+    let fail = |e: io::Error| format!("{}: {e}", path.display());
+    // A socket left behind by an earlier run would make binding fail
+    let stale =
+        std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket());
+    if stale {
+        std::fs::remove_file(path).map_err(fail)?;
+    }
+    let unix = UnixListener::bind(path).map_err(fail)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, Permissions::from_mode(mode)).map_err(fail)?;
+    }
+    Ok(Listener::Unix(unix, Some(path.to_owned())))
+}
+
+fn adopt(fd: RawFd) -> Result<Listener, String> {
+    let fail = |e: io::Error| format!("socket {fd} from systemd: {e}");
+    let mut domain: libc::c_int = 0;
+    let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: getsockopt only writes to `domain` and `length`, which are
+    // valid for the sizes given, and fcntl only changes the descriptor's flags
+    let result = unsafe {
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_DOMAIN,
+            (&mut domain as *mut libc::c_int).cast(),
+            &mut length,
+        )
+    };
+    if result != 0 {
+        return Err(fail(io::Error::last_os_error()));
+    }
+    if domain == libc::AF_UNIX {
+        // SAFETY: systemd passed this descriptor for us to own, and it's
+        // only adopted once
+        let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+        unix.set_nonblocking(true).map_err(fail)?;
+        let unix = UnixListener::from_std(unix).map_err(fail)?;
+        Ok(Listener::Unix(unix, None))
+    } else {
+        // SAFETY: as above
+        let tcp = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        tcp.set_nonblocking(true).map_err(fail)?;
+        TcpListener::from_std(tcp).map(Listener::Tcp).map_err(fail)
+    }
+}
+
+/// Serve `app` on every listener until shutdown, letting requests in flight
+/// finish. With `tls`, TCP listeners serve HTTPS; unix sockets never do,
+/// since they don't leave the machine.
+pub async fn serve(listeners: Vec<Listener>, app: Router, tls: Option<Arc<Tls>>) {
+    let graceful = Arc::new(GracefulShutdown::new());
+    let mut accepting = JoinSet::new();
+    let mut sockets = Vec::new();
+    for listener in listeners {
+        let (app, graceful) = (app.clone(), graceful.clone());
+        match listener {
+            Listener::Tcp(tcp) => accepting.spawn(accept_tcp(tcp, app, tls.clone(), graceful)),
+            Listener::Unix(unix, path) => {
+                sockets.extend(path);
+                accepting.spawn(accept_unix(unix, app, graceful))
+            }
+        };
+    }
+    vss::shutdown_signal().await;
+    accepting.abort_all();
+    while accepting.join_next().await.is_some() {}
+    for path in sockets {
+        if let Err(source) = std::fs::remove_file(&path) {
+            eprintln!("Error removing {}: {source:?}", path.display());
+        }
+    }
+    if let Ok(graceful) = Arc::try_unwrap(graceful) {
+        graceful.shutdown().await;
+    }
+}
+
+async fn accept_tcp(
+    tcp: TcpListener,
+    app: Router,
+    tls: Option<Arc<Tls>>,
+    graceful: Arc<GracefulShutdown>,
+) {
+    loop {
+        let (stream, peer) = match tcp.accept().await {
+            Ok(accepted) => accepted,
+            Err(source) => {
+                accept_failed(source).await;
+                continue;
+            }
+        };
+        let (app, tls, watcher) = (app.clone(), tls.clone(), graceful.watcher());
+        tokio::spawn(async move {
+            match tls {
+                Some(tls) => {
+                    if let Some((stream, client)) = tls.accept(stream, &peer.to_string()).await {
+                        connection(stream, app, client, watcher).await;
+                    }
+                }
+                None => connection(stream, app, None, watcher).await,
+            }
+        });
+    }
+}
+
+async fn accept_unix(unix: UnixListener, app: Router, graceful: Arc<GracefulShutdown>) {
+    loop {
+        match unix.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(connection(stream, app.clone(), None, graceful.watcher()));
+            }
+            Err(source) => accept_failed(source).await,
+        }
+    }
+}
+
+/// Running out of file descriptors fails every accept until some close, so
+/// back off instead of spinning.
+async fn accept_failed(source: io::Error) {
+    eprintln!("Error: {source:?}");
+    tokio::time::sleep(Duration::from_secs(1)).await;
+}
+
+/// Serve HTTP on one connection, telling handlers who the client certificate
+/// belongs to, if anyone.
+async fn connection<S>(stream: S, app: Router, client: Option<ClientIdentity>, watcher: Watcher)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |mut request: Request<Incoming>| {
+        if let Some(client) = &client {
+            request.extensions_mut().insert(client.clone());
+        }
+        // Routers are always ready, so there's no need to poll first
+        app.clone().call(request.map(Body::new))
+    });
+    let connection = http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+    // Clients hanging up early isn't our problem
+    let _ = watcher.watch(connection).await;
+}
@@ -0,0 +1,75 @@
+use std::{convert::Infallible, sync::Arc};
+
+use axum::{
+    extract::{Path, State},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures_util::{stream, Stream, StreamExt};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    auth::{Caller, Scope},
+    queue::{OutputLine, Run, Stream as OutputStream},
+    AppState, Error,
+};
+
+/// Streams a job's output as server-sent events, starting with what it has
+/// already printed.
+pub async fn job_log_web(
+    Path(id): Path<u64>,
+    State(state): State<Arc<AppState>>,
+    Caller(identity): Caller,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, Error> {
+    let job = state.jobs.get(id).ok_or(Error::NoJob(id))?;
+    identity.check(Scope::Status(&job.composition))?;
+    Ok(follow(job.run.clone()))
+}
+
+/// Streams the output of a composition's current restart (or the one waiting
+/// to start, or else the last one) as server-sent events.
+pub async fn composition_log_web(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Caller(identity): Caller,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, Error> {
+    identity.check(Scope::Status(&name))?;
+    if !state.config.extra.contains_key(&name) {
+        return Err(Error::NoComposition(name));
+    }
+    let run = state.queues.latest(&name).ok_or(Error::NoRun(name))?;
+    Ok(follow(run))
+}
+
+/// Each line is an `stdout` or `stderr` event. A final `done` event carries
+/// the outcome, and then the stream ends.
+fn follow(run: Arc<Run>) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let (backlog, receiver) = run.follow();
+    let replay = stream::iter(backlog).map(line_event);
+    let live = stream::unfold(receiver, |receiver| async move {
+        let mut receiver = receiver?;
+        loop {
+            match receiver.recv().await {
+                Ok(Some(line)) => return Some((line_event(line), Some(receiver))),
+                Ok(None) | Err(RecvError::Closed) => return None,
+                Err(RecvError::Lagged(_)) => continue,
+            }
+        }
+    });
+    let done = stream::once(async move {
+        let data = match run.outcome() {
+            Some(Ok(deployment)) => deployment.to_string(),
+            Some(Err(source)) => source.to_string(),
+            None => String::new(),
+        };
+        Event::default().event("done").data(data.trim_end())
+    });
+    Sse::new(replay.chain(live).chain(done).map(Ok)).keep_alive(KeepAlive::default())
+}
+
+fn line_event(line: OutputLine) -> Event {
+    let name = match line.stream {
+        OutputStream::Stdout => "stdout",
+        OutputStream::Stderr => "stderr",
+    };
+    Event::default().event(name).data(line.text)
+}
@@ -1,42 +1,171 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+mod auth;
+mod compose;
+mod docker_config;
+mod engine;
+mod freeze;
+mod gitea;
+mod github;
+mod gitlab;
+mod image;
+mod jobs;
+mod listen;
+mod logs;
+mod order;
+mod probe;
+mod process;
+mod push;
+mod queue;
+mod registry;
+mod rollback;
+mod runtime;
+mod schedule;
+mod secret;
+mod tags;
+mod tls;
+mod webhook;
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::Display,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
 };
-use axum_extra::{
-    headers::{authorization::Bearer, Authorization},
-    TypedHeader,
+use tokio::{select, task::JoinSet, time::MissedTickBehavior};
+
+use crate::{
+    auth::{Caller, NamedToken, Scope, Tokens},
+    compose::Project,
+    engine::{ContainerEvent, Engine, PullOutcome},
+    freeze::{Blocked, Freeze, FreezePolicy, Freezes, Window},
+    image::ImageRef,
+    jobs::Jobs,
+    listen::Listen,
+    probe::{Probe, ProbeResult},
+    queue::{Queues, Run},
+    rollback::{RollbackSettings, Snapshot},
+    runtime::Runtime,
+    schedule::Schedule,
+    secret::{Secret, Token},
+    tags::TagTracking,
+    tls::{Tls, TlsSettings},
+    webhook::WebhookTrigger,
 };
-use tokio::{net::TcpListener, select, task::JoinSet, time::MissedTickBehavior};
 
-#[tokio::main]
-async fn main() {
+fn main() {
+    // Before the runtime starts its threads, since this changes the environment
+    let inherited = listen::Inherited::take();
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Could not start the runtime")
+        .block_on(run(inherited));
+}
+
+async fn run(inherited: listen::Inherited) {
     let cfg_path = std::env::args()
         .nth(1)
         .unwrap_or_else(|| "/etc/conductor/config.toml".to_string());
     let config_str =
-        std::fs::read_to_string(cfg_path).expect("Expected config to exist and be valid utf-8");
+        std::fs::read_to_string(&cfg_path).expect("Expected config to exist and be valid utf-8");
     let config: Config = toml::from_str(&config_str).expect("Invalid config toml");
-    let config = Arc::new(config);
+    order::validate(&config).expect("Invalid depends_on");
+    tls::validate(&config).expect("Invalid tls clients");
+    rollback::validate(&config).expect("Invalid rollback");
+    tags::validate(&config).expect("Invalid tags");
+    let tokens = Tokens::from_config(&config).expect("Invalid tokens");
+    if config
+        .extra
+        .values()
+        .any(|composition| config.runtime_for(composition).pulls_with_engine())
+    {
+        Engine::from_env().expect("Invalid DOCKER_HOST");
+    }
+    let listeners = listen::bind(&config, inherited)
+        .await
+        .expect("Could not listen");
+    let tls = config
+        .tls
+        .as_ref()
+        .map(|settings| Arc::new(Tls::new(settings).expect("Invalid TLS certificate")));
+    let state = Arc::new(AppState {
+        config,
+        queues: Queues::default(),
+        jobs: Jobs::default(),
+        freezes: Freezes::default(),
+        tokens: RwLock::new(tokens),
+        config_path: cfg_path,
+    });
     let mut workers = JoinSet::new();
-    if let Some(secs) = config.force_update_interval {
-        workers.spawn(restart_all(secs, config.clone()));
+    workers.spawn(auth::reload_on_hangup(state.clone()));
+    if let Some(secs) = state.config.force_update_interval {
+        workers.spawn(restart_all(secs, state.clone()));
+    }
+    for (name, composition) in &state.config.extra {
+        if composition.schedule.is_some() {
+            workers.spawn(schedule::run(name.clone(), state.clone()));
+        }
+    }
+    if let Some(secs) = state.config.poll_interval {
+        workers.spawn(registry::poll(secs, state.clone()));
     }
-    if let Some(secs) = config.prune_interval {
-        workers.spawn(prune(secs));
+    if let Some(secs) = state.config.prune_interval {
+        workers.spawn(prune(secs, state.clone()));
     }
-    let port = config.port;
     let app = axum::Router::new()
         .route("/:path", axum::routing::any(restart_web))
-        .with_state(config);
-    let bind_address = SocketAddr::from(([0, 0, 0, 0], port));
-    println!("Starting server on http://localhost:8080");
-    let tcp = TcpListener::bind(bind_address).await.unwrap();
-    axum::serve(tcp, app)
-        .with_graceful_shutdown(vss::shutdown_signal())
-        .await
-        .unwrap();
+        .route(
+            "/jobs/:id",
+            axum::routing::get(jobs::job_web).delete(jobs::cancel_job_web),
+        )
+        .route(
+            "/freeze",
+            axum::routing::post(freeze::freeze_all_web).delete(freeze::thaw_all_web),
+        )
+        .route(
+            "/freeze/:path",
+            axum::routing::post(freeze::freeze_web).delete(freeze::thaw_web),
+        )
+        .route("/:path/:service", axum::routing::any(restart_service_web))
+        .route("/prune", axum::routing::post(prune_web))
+        .route("/jobs/:id/log", axum::routing::get(logs::job_log_web))
+        .route("/logs/:path", axum::routing::get(logs::composition_log_web))
+        .route("/github/:path", axum::routing::post(github::github_web))
+        .route("/gitlab/:path", axum::routing::post(gitlab::gitlab_web))
+        .route("/gitea/:path", axum::routing::post(gitea::gitea_web))
+        .route("/forgejo/:path", axum::routing::post(gitea::forgejo_web))
+        .route(
+            "/registry/dockerhub",
+            axum::routing::post(push::dockerhub_web),
+        )
+        .route("/registry/harbor", axum::routing::post(push::harbor_web))
+        .route(
+            "/registry/distribution",
+            axum::routing::post(push::distribution_web),
+        )
+        .with_state(state.clone());
+    if let (Some(tls), Some(settings)) = (&tls, &state.config.tls) {
+        workers.spawn(tls::reload_on_change(tls.clone()));
+        let addresses = listen::tcp_addresses(&listeners);
+        if let (Some(redirect_port), Some(https)) = (settings.redirect_port, addresses.first()) {
+            let redirect_from = addresses
+                .iter()
+                .map(|address| SocketAddr::new(address.ip(), redirect_port))
+                .collect();
+            workers.spawn(tls::redirect(redirect_from, https.port()));
+        }
+    }
+    for listener in &listeners {
+        println!("Listening on {}", listener.describe(tls.is_some()));
+    }
+    listen::serve(listeners, app, tls).await;
     while let Some(val) = workers.join_next().await {
         if let Err(err) = val {
             eprintln!("Error on shutdown: {err:?}");
@@ -44,23 +173,84 @@ async fn main() {
     }
 }
 
+#[derive(serde::Deserialize)]
+struct RestartQuery {
+    #[serde(default, rename = "async")]
+    asynchronous: bool,
+}
+
+/// Restarts a composition. With `?async=true` or `Prefer: respond-async`, this
+/// answers `202 Accepted` with a job to poll at `/jobs/:id` instead of waiting,
+/// as it does when a freeze holds the restart back.
 async fn restart_web(
     Path(name): Path<String>,
-    State(state): State<Arc<Config>>,
-    TypedHeader(Authorization(auth)): TypedHeader<Authorization<Bearer>>,
-) -> Result<(StatusCode, &'static str), Error> {
-    if state.token != auth.token() {
-        return Err(Error::Unauthorized);
-    }
-    if let Err(source) = restart(&name, state).await {
-        eprintln!("Error: {source:?}");
-        Err(source)
-    } else {
-        Ok((StatusCode::OK, "Success\n"))
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<RestartQuery>,
+    headers: HeaderMap,
+    Caller(identity): Caller,
+) -> Result<Response, Error> {
+    identity.check(Scope::Restart(&name))?;
+    respond(&name, None, state, &query, &headers).await
+}
+
+/// Pulls and recreates a single service of a composition, if it's listed in
+/// the composition's `services`. Otherwise like `restart_web`.
+async fn restart_service_web(
+    Path((name, service)): Path<(String, String)>,
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<RestartQuery>,
+    headers: HeaderMap,
+    Caller(identity): Caller,
+) -> Result<Response, Error> {
+    identity.check(Scope::Restart(&name))?;
+    let Some(composition) = state.config.extra.get(&name) else {
+        return Err(Error::NoComposition(name));
+    };
+    if !composition.services.contains(&service) {
+        return Err(Error::ServiceNotAllowed(service));
+    }
+    respond(&name, Some(&service), state, &query, &headers).await
+}
+
+async fn respond(
+    name: &str,
+    service: Option<&str>,
+    state: Arc<AppState>,
+    query: &RestartQuery,
+    headers: &HeaderMap,
+) -> Result<Response, Error> {
+    let respond_async = headers
+        .get_all("prefer")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.split(',').any(|p| p.trim() == "respond-async"));
+    // A restart held back by a freeze could take hours, so don't wait for it
+    if query.asynchronous || respond_async || freeze::blocked(&state, name).is_some() {
+        return accept(name, service, &state);
     }
+    match enqueue(name, service, &state)?.wait().await {
+        Ok(deployment) => Ok((StatusCode::OK, deployment.to_string()).into_response()),
+        Err(source) => {
+            eprintln!("Error: {source:?}");
+            Err(source)
+        }
+    }
+}
+
+/// Queue a restart as a job, answering with where to follow it and whether a
+/// freeze is holding it back.
+fn accept(name: &str, service: Option<&str>, state: &Arc<AppState>) -> Result<Response, Error> {
+    let deferred = freeze::blocked(state, name);
+    let run = enqueue(name, service, state)?;
+    let job = state.jobs.create(name, run);
+    let mut body = serde_json::json!({ "id": job.id, "status_url": format!("/jobs/{}", job.id) });
+    if let Some(blocked) = deferred {
+        body["deferred"] = blocked.to_string().into();
+    }
+    Ok((StatusCode::ACCEPTED, Json(body)).into_response())
 }
 
-async fn restart_all(secs: u64, config: Arc<Config>) {
+async fn restart_all(secs: u64, state: Arc<AppState>) {
     let period = Duration::from_secs(secs);
     let mut ticker = tokio::time::interval(period);
     ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
@@ -69,38 +259,278 @@ async fn restart_all(secs: u64, config: Arc<Config>) {
             _ = tokio::signal::ctrl_c() => break,
             _ = ticker.tick() => {}
         }
-        for name in config.extra.keys() {
-            if let Err(source) = restart(name, config.clone()).await {
-                eprintln!("Error: {source:?}")
-            }
+        // Those with their own schedule only restart on it
+        let names = state
+            .config
+            .extra
+            .iter()
+            .filter(|(_, composition)| composition.schedule.is_none())
+            .map(|(name, _)| name.clone())
+            .collect();
+        order::restart(names, state.clone()).await;
+    }
+}
+
+/// Restart a composition, waiting behind (or merging with) any restart of it
+/// that is already underway.
+async fn deploy(name: &str, state: Arc<AppState>) -> Result<Arc<Deployment>, Error> {
+    enqueue(name, None, &state)?.wait().await
+}
+
+/// Queue a restart of a composition, or of one of its services, without
+/// waiting for it.
+fn enqueue(name: &str, service: Option<&str>, state: &Arc<AppState>) -> Result<Arc<Run>, Error> {
+    if !state.config.extra.contains_key(name) {
+        return Err(Error::NoComposition(name.to_owned()));
+    }
+    if freeze::policy(state, name) == FreezePolicy::Reject {
+        if let Some(blocked) = freeze::blocked(state, name) {
+            return Err(Error::Frozen(blocked));
         }
     }
+    let task_state = state.clone();
+    let task_name = name.to_owned();
+    let task_service = service.map(ToOwned::to_owned);
+    Ok(state.queues.enqueue(name, service, move |run| async move {
+        freeze::wait_open(&task_name, &task_state, &run).await;
+        let config = &task_state.config;
+        let composition = &config.extra[&task_name];
+        let restart = restart(&task_name, task_service.as_deref(), config, &run);
+        match config.timeout_for(composition) {
+            Some(limit) => tokio::time::timeout(limit, restart)
+                .await
+                .unwrap_or(Err(Error::Timeout(limit.as_secs()))),
+            None => restart.await,
+        }
+    }))
 }
 
-async fn restart(name: &str, config: Arc<Config>) -> Result<(StatusCode, &'static str), Error> {
+async fn restart(
+    name: &str,
+    service: Option<&str>,
+    config: &Config,
+    run: &Run,
+) -> Result<Deployment, Error> {
     let Some(composition) = config.extra.get(name) else {
         return Err(Error::NoComposition(name.to_owned()));
     };
-    let pull_task = tokio::process::Command::new("docker")
-        .arg("compose")
-        .arg("up")
-        .arg("-d")
-        .arg("--pull")
-        .arg("always")
-        .current_dir(&composition.work)
-        .spawn()?;
-    let output = pull_task.wait_with_output().await?;
-    if !output.status.success() {
-        Err(Error::PullFailed {
-            stdout: String::from_utf8_lossy(&output.stdout).into(),
-            stderr: String::from_utf8_lossy(&output.stderr).into(),
-        })
+    let runtime = config.runtime_for(composition);
+    let (mut pull, mut up) = (vec!["pull"], vec!["up", "-d"]);
+    if let Some(service) = service {
+        if composition.no_deps {
+            up.push("--no-deps");
+        }
+        pull.push(service);
+        up.push(service);
+    }
+    if !runtime.pulls_with_engine() {
+        compose::run(runtime, composition, &pull, run).await?;
+        compose::run(runtime, composition, &up, run).await?;
+        let probes = run_probes(name, composition, runtime).await;
+        if probes.iter().any(|probe| !probe.passed) {
+            return Err(Error::ProbeFailed(probes));
+        }
+        return Ok(Deployment {
+            probes,
+            ..Default::default()
+        });
+    }
+    let engine = Engine::from_env()?;
+    let mut project = Project::load(runtime, composition).await?;
+    if let Some(service) = service {
+        project.services.retain(|name, _| name == service);
+    }
+    let before = Snapshot::take(&engine, &project.name).await?;
+    let mut pulls = Vec::new();
+    for image in project.pullable_images() {
+        pulls.push(engine.pull(&image).await?);
+    }
+    let changes = image_changes(&project, &before, &pulls);
+    for change in &changes {
+        println!("[{name}] {change}");
+    }
+    if changes.is_empty()
+        && !composition.always_up
+        && up_to_date(&project, &before, runtime, composition).await?
+    {
+        println!("[{name}] Nothing changed, not recreating anything");
+        return Ok(Deployment {
+            pulls,
+            skipped: true,
+            ..Default::default()
+        });
+    }
+    let started = unix_now();
+    compose::run(runtime, composition, &up, run).await?;
+    let events = engine
+        .project_events(&project.name, started, unix_now() + 1)
+        .await?;
+    let mut unhealthy = match &composition.rollback {
+        Some(settings) => rollback::wait_healthy(&engine, &project.name, &events, settings).await?,
+        None => Vec::new(),
+    };
+    let probes = if unhealthy.is_empty() {
+        run_probes(name, composition, runtime).await
     } else {
-        Ok((StatusCode::OK, "Success\n"))
+        Vec::new()
+    };
+    let failed_probes = probes.iter().filter(|probe| !probe.passed);
+    unhealthy.extend(failed_probes.map(|probe| probe.probe.clone()));
+    if composition.rollback.is_some() && !unhealthy.is_empty() {
+        let restored =
+            rollback::roll_back(&engine, runtime, composition, &project, &before, run).await?;
+        return Err(if restored.is_empty() {
+            Error::Unhealthy(unhealthy)
+        } else {
+            Error::RolledBack {
+                unhealthy,
+                restored,
+            }
+        });
+    }
+    if probes.iter().any(|probe| !probe.passed) {
+        return Err(Error::ProbeFailed(probes));
+    }
+    Ok(Deployment {
+        pulls,
+        changes,
+        skipped: false,
+        events,
+        probes,
+    })
+}
+
+/// Whether `up` would leave every service as it is: each one runs a pulled
+/// image, since built images can't be compared, and was created from the
+/// configuration compose resolves now, so edits to compose and env files
+/// still get applied.
+async fn up_to_date(
+    project: &Project,
+    before: &Snapshot,
+    runtime: Runtime,
+    composition: &ManagedComposition,
+) -> Result<bool, Error> {
+    let pulled_only = project
+        .services
+        .values()
+        .all(|service| service.image.is_some() && service.build.is_none());
+    if !pulled_only {
+        return Ok(false);
     }
+    let hashes = Project::config_hashes(runtime, composition).await?;
+    Ok(project.services.keys().all(|service| {
+        before.image(service).is_some()
+            && hashes
+                .get(service)
+                .is_some_and(|hash| before.config_hash(service) == Some(hash))
+    }))
 }
 
-async fn prune(secs: u64) {
+/// Services whose running image differs from the one just pulled for them.
+fn image_changes(project: &Project, before: &Snapshot, pulls: &[PullOutcome]) -> Vec<ImageChange> {
+    let mut changes = Vec::new();
+    for (service, config) in &project.services {
+        let Some(image) = config.image.as_deref().filter(|_| config.build.is_none()) else {
+            continue;
+        };
+        let image = ImageRef::parse(image);
+        let Some(pull) = pulls.iter().find(|pull| pull.image == image) else {
+            continue;
+        };
+        let old = before.image(service);
+        if old != Some(pull.id.as_str()) {
+            changes.push(ImageChange {
+                service: service.clone(),
+                old: old.map(ToOwned::to_owned),
+                new: pull.id.clone(),
+            });
+        }
+    }
+    changes
+}
+
+async fn run_probes(
+    name: &str,
+    composition: &ManagedComposition,
+    runtime: Runtime,
+) -> Vec<ProbeResult> {
+    if composition.probes.is_empty() {
+        return Vec::new();
+    }
+    let deadline = Duration::from_secs(composition.probe_timeout);
+    probe::run_all(name, &composition.probes, deadline, runtime, composition).await
+}
+
+/// What a successful restart did, for logs and HTTP responses.
+#[derive(Default)]
+pub struct Deployment {
+    pulls: Vec<PullOutcome>,
+    changes: Vec<ImageChange>,
+    /// Whether `up` was skipped because nothing changed
+    skipped: bool,
+    events: Vec<ContainerEvent>,
+    probes: Vec<ProbeResult>,
+}
+
+impl Display for Deployment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Success")?;
+        for pull in &self.pulls {
+            if pull.updated {
+                writeln!(f, "{}: updated to {}", pull.image, pull.id)?;
+            } else {
+                writeln!(f, "{}: up to date", pull.image)?;
+            }
+        }
+        for change in &self.changes {
+            writeln!(f, "{change}")?;
+        }
+        if self.skipped {
+            writeln!(f, "Nothing changed, not recreating anything")?;
+        }
+        let mut actions: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for event in &self.events {
+            if let Some(service) = event.service() {
+                actions.entry(service).or_default().push(&event.action);
+            }
+        }
+        for (service, actions) in actions {
+            writeln!(f, "{service}: {}", actions.join(", "))?;
+        }
+        for probe in &self.probes {
+            writeln!(f, "{probe}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A service that gets a different image than the one it was running.
+pub struct ImageChange {
+    service: String,
+    old: Option<String>,
+    new: String,
+}
+
+impl Display for ImageChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let old = self.old.as_deref().map_or("nothing", short_id);
+        write!(f, "{}: {old} -> {}", self.service, short_id(&self.new))
+    }
+}
+
+/// An image ID shortened the way `docker images` shows it.
+fn short_id(id: &str) -> &str {
+    let id = id.strip_prefix("sha256:").unwrap_or(id);
+    id.get(..12).unwrap_or(id)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+async fn prune(secs: u64, state: Arc<AppState>) {
     let period = Duration::from_secs(secs);
     let mut ticker = tokio::time::interval(period);
     ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
@@ -109,74 +539,362 @@ async fn prune(secs: u64) {
             _ = tokio::signal::ctrl_c() => break,
             _ = ticker.tick() => {}
         }
-        if let Err(source) = do_prune().await {
-            eprintln!("Error: {source:?}")
+        match prune_once(&state.config).await {
+            Ok(report) => print!("{report}"),
+            Err(source) => eprintln!("Error: {source:?}"),
         }
     }
 }
 
-async fn do_prune() -> Result<(), Error> {
-    let pull_task = tokio::process::Command::new("docker")
-        .arg("image")
-        .arg("prune")
-        .arg("-a")
-        .arg("-f")
-        .spawn()?;
-    let output = pull_task.wait_with_output().await?;
-    if !output.status.success() {
-        Err(Error::PruneFailed {
-            stdout: String::from_utf8_lossy(&output.stdout).into(),
-            stderr: String::from_utf8_lossy(&output.stderr).into(),
-        })
-    } else {
-        Ok(())
+/// Prunes unused images right away.
+async fn prune_web(
+    State(state): State<Arc<AppState>>,
+    Caller(identity): Caller,
+) -> Result<String, Error> {
+    identity.check(Scope::Prune)?;
+    match prune_once(&state.config).await {
+        Ok(report) => {
+            print!("{report}");
+            Ok(report)
+        }
+        Err(source) => {
+            eprintln!("Error: {source:?}");
+            Err(source)
+        }
+    }
+}
+
+async fn prune_once(config: &Config) -> Result<String, Error> {
+    match config.timeout {
+        Some(secs) => tokio::time::timeout(Duration::from_secs(secs), do_prune(config))
+            .await
+            .unwrap_or(Err(Error::Timeout(secs))),
+        None => do_prune(config).await,
+    }
+}
+
+async fn do_prune(config: &Config) -> Result<String, Error> {
+    let mut report = String::new();
+    let runtimes: HashSet<Runtime> = config
+        .extra
+        .values()
+        .map(|composition| config.runtime_for(composition))
+        .collect();
+    if runtimes.iter().any(|runtime| runtime.uses_engine()) {
+        let pruned = Engine::from_env()?.prune_images().await?;
+        report.push_str(&format!(
+            "Pruned {} images, reclaiming {} bytes\n",
+            pruned.images_deleted(),
+            pruned.space_reclaimed
+        ));
+    }
+    for runtime in runtimes.into_iter().filter(|r| !r.uses_engine()) {
+        let output = process::capture(runtime.prune(), "prune", None).await?;
+        if !output.status.success() {
+            return Err(Error::PruneFailed {
+                reason: runtime.parse_error(&output.stderr),
+                stdout: output.stdout,
+                stderr: output.stderr,
+            });
+        }
+        report.push_str(&format!("Pruned {runtime} images\n"));
     }
+    Ok(report)
+}
+
+pub struct AppState {
+    config: Config,
+    queues: Queues,
+    jobs: Jobs,
+    freezes: Freezes,
+    /// Replaced when the config is reloaded, unlike the rest of the config
+    tokens: RwLock<Tokens>,
+    config_path: String,
 }
 
 #[derive(serde::Deserialize)]
 pub struct Config {
     #[serde(default = "default_port")]
     port: u16,
-    token: String,
+    /// Addresses and unix sockets to listen on, instead of every IPv4
+    /// address on `port`
+    #[serde(default)]
+    listen: Vec<Listen>,
+    /// Serve HTTPS instead of plain HTTP
+    tls: Option<TlsSettings>,
+    /// A token that may do anything
+    token: Option<Token>,
+    /// A file holding `token`, like a Docker secret
+    #[serde(default, deserialize_with = "secret::from_file")]
+    token_file: Option<Secret>,
+    /// Tokens limited to some scopes, by the name they are logged under
+    #[serde(default)]
+    tokens: BTreeMap<String, NamedToken>,
+    #[serde(default)]
+    runtime: Runtime,
+    /// Seconds a restart or prune may take before it's killed
+    timeout: Option<u64>,
     force_update_interval: Option<u64>,
     prune_interval: Option<u64>,
+    /// When restarts may happen, unless a composition has windows of its own
+    #[serde(default)]
+    windows: Vec<Window>,
+    /// When no composition may restart
+    #[serde(default)]
+    freezes: Vec<Freeze>,
+    /// What to do with restarts requested outside windows or during a freeze
+    #[serde(default)]
+    freeze_policy: FreezePolicy,
+    /// Seconds between checks of the registries for compositions with `poll` set
+    poll_interval: Option<u64>,
+    /// Registries to speak plain HTTP to, like `localhost:5000`
+    #[serde(default)]
+    insecure_registries: Vec<String>,
+    /// How many compositions `force_update_interval` restarts at once
+    #[serde(default = "default_concurrency")]
+    concurrency: usize,
+    /// Whether a failed restart stops the compositions depending on it
+    #[serde(default = "default_halt_dependents")]
+    halt_dependents: bool,
     #[serde(flatten)]
     extra: HashMap<String, ManagedComposition>,
 }
 
+impl Config {
+    fn runtime_for(&self, composition: &ManagedComposition) -> Runtime {
+        composition.runtime.unwrap_or(self.runtime)
+    }
+
+    fn timeout_for(&self, composition: &ManagedComposition) -> Option<Duration> {
+        composition
+            .timeout
+            .or(self.timeout)
+            .map(Duration::from_secs)
+    }
+}
+
 #[derive(serde::Deserialize)]
 pub struct ManagedComposition {
     work: String,
+    /// A token that may only restart this composition and read its status
+    token: Option<Token>,
+    /// A file holding `token`
+    #[serde(default, deserialize_with = "secret::from_file")]
+    token_file: Option<Secret>,
+    /// Compose files, relative to `work`, to use instead of the default one
+    #[serde(default)]
+    files: Vec<String>,
+    /// Project name to use instead of the name of `work`
+    project_name: Option<String>,
+    #[serde(default)]
+    profiles: Vec<String>,
+    /// Files to read variables from, relative to `work`, instead of `.env`
+    #[serde(default)]
+    env_files: Vec<String>,
+    /// Extra environment variables for every compose command
+    #[serde(default)]
+    environment: BTreeMap<String, String>,
+    runtime: Option<Runtime>,
+    timeout: Option<u64>,
+    rollback: Option<RollbackSettings>,
+    /// Run `up` even when nothing seems to have changed
+    #[serde(default)]
+    always_up: bool,
+    schedule: Option<Schedule>,
+    /// Restart whenever the registry has new images for this composition
+    #[serde(default)]
+    poll: bool,
+    /// Services whose image tags move forward on every poll, by service name
+    #[serde(default)]
+    tags: BTreeMap<String, TagTracking>,
+    /// Services that may be restarted on their own, at `/:composition/:service`
+    #[serde(default)]
+    services: Vec<String>,
+    /// Leave the services a single service depends on alone when restarting it
+    #[serde(default)]
+    no_deps: bool,
+    /// Compositions to restart before this one
+    #[serde(default)]
+    depends_on: BTreeSet<String>,
+    #[serde(default)]
+    windows: Vec<Window>,
+    #[serde(default)]
+    freezes: Vec<Freeze>,
+    freeze_policy: Option<FreezePolicy>,
+    /// Readiness checks that must pass before a restart succeeds
+    #[serde(default)]
+    probes: Vec<Probe>,
+    /// Seconds the probes have to pass
+    #[serde(default = "default_probe_timeout")]
+    probe_timeout: u64,
+    /// Image patterns this composition consumes, for registry push events
+    #[serde(default)]
+    images: Vec<String>,
+    github: Option<WebhookTrigger>,
+    gitlab: Option<WebhookTrigger>,
+    gitea: Option<WebhookTrigger>,
+    forgejo: Option<WebhookTrigger>,
 }
 
 fn default_port() -> u16 {
     8080
 }
 
+fn default_concurrency() -> usize {
+    1
+}
+
+fn default_halt_dependents() -> bool {
+    true
+}
+
+fn default_probe_timeout() -> u64 {
+    60
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("I/O error\n")]
     Io(#[from] std::io::Error),
-    #[error("Docker pull failed\n")]
-    PullFailed { stdout: String, stderr: String },
-    #[error("Docker prune failed\n")]
-    PruneFailed { stdout: String, stderr: String },
+    #[error("Pull failed: {reason}\n")]
+    PullFailed {
+        reason: String,
+        stdout: String,
+        stderr: String,
+    },
+    #[error("Prune failed: {reason}\n")]
+    PruneFailed {
+        reason: String,
+        stdout: String,
+        stderr: String,
+    },
+    #[error("Invalid compose project: {0}\n")]
+    ComposeConfig(String),
+    #[error("Docker engine returned {status}: {message}\n")]
+    Engine { status: u16, message: String },
+    #[error("Failed to pull {image}: {message}\n")]
+    ImagePull { image: String, message: String },
+    #[error("Failed to check {image}: {message}\n")]
+    Registry { image: String, message: String },
+    #[error("Can't track tags: {0}\n")]
+    TagTracking(String),
+    #[error("Unsupported DOCKER_HOST `{0}`\n")]
+    UnsupportedDockerHost(String),
+    #[error("DOCKER_TLS_VERIFY and DOCKER_CERT_PATH aren't supported, so `{0}` would be used without TLS\n")]
+    UnsupportedDockerTls(String),
+    #[error("Docker engine connection failed\n")]
+    Http(#[from] hyper::Error),
+    #[error("Invalid docker engine request\n")]
+    Request(#[from] axum::http::Error),
     #[error("No composition found for path `{0}`\n")]
     NoComposition(String),
     #[error("Unauthorized user attempted to access server\n")]
     Unauthorized,
+    #[error("No job with id {0}\n")]
+    NoJob(u64),
+    #[error("Composition `{0}` has not been restarted yet\n")]
+    NoRun(String),
+    #[error("No webhook configured for composition `{0}`\n")]
+    NoWebhook(String),
+    #[error("Webhook signature did not match\n")]
+    InvalidSignature,
+    #[error("Invalid request body: {0}\n")]
+    Payload(#[from] serde_json::Error),
+    #[error("Deployment task stopped unexpectedly\n")]
+    TaskFailed,
+    #[error("Timed out after {0} seconds\n")]
+    Timeout(u64),
+    #[error("Services never became healthy: {}\n", .0.join(", "))]
+    Unhealthy(Vec<String>),
+    #[error(
+        "Rolled back {} because services never became healthy: {}\n",
+        .restored.join(", "),
+        .unhealthy.join(", ")
+    )]
+    RolledBack {
+        unhealthy: Vec<String>,
+        restored: Vec<String>,
+    },
+    #[error("Readiness probes failed:\n{}", failed_probes(.0))]
+    ProbeFailed(Vec<ProbeResult>),
+    #[error("{0}\n")]
+    Frozen(Blocked),
+    #[error("Service {0} can't be restarted on its own\n")]
+    ServiceNotAllowed(String),
+    #[error("Token may not do that\n")]
+    Forbidden,
+    #[error("Cancelled\n")]
+    Cancelled,
+    #[error("Job {0} has already finished\n")]
+    JobFinished(u64),
+    #[error(transparent)]
+    Shared(Arc<Error>),
+}
+
+fn failed_probes(results: &[ProbeResult]) -> String {
+    let mut out = String::new();
+    for result in results.iter().filter(|result| !result.passed) {
+        out.push_str(&result.to_string());
+        out.push('\n');
+    }
+    out
+}
+
+/// How much of a failed command's stderr to send back to the client.
+const STDERR_TAIL_LINES: usize = 20;
+const STDERR_TAIL_BYTES: usize = 4096;
+
+impl Error {
+    /// Output from the failed command, if this error came from one.
+    fn stderr(&self) -> Option<&str> {
+        match self {
+            Error::PullFailed { stderr, .. } | Error::PruneFailed { stderr, .. } => Some(stderr),
+            Error::Shared(source) => source.stderr(),
+            _ => None,
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            Error::Io(_)
+            | Error::PullFailed { .. }
+            | Error::PruneFailed { .. }
+            | Error::ComposeConfig(_)
+            | Error::Engine { .. }
+            | Error::ImagePull { .. }
+            | Error::Registry { .. }
+            | Error::TagTracking(_)
+            | Error::UnsupportedDockerHost(_)
+            | Error::UnsupportedDockerTls(_)
+            | Error::Http(_)
+            | Error::Request(_)
+            | Error::TaskFailed
+            | Error::Unhealthy(_)
+            | Error::RolledBack { .. }
+            | Error::ProbeFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Unauthorized | Error::InvalidSignature => StatusCode::UNAUTHORIZED,
+            Error::NoComposition(_) | Error::NoWebhook(_) | Error::NoJob(_) | Error::NoRun(_) => {
+                StatusCode::NOT_FOUND
+            }
+            Error::Payload(_) => StatusCode::BAD_REQUEST,
+            Error::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Error::Cancelled | Error::JobFinished(_) => StatusCode::CONFLICT,
+            Error::Frozen(_) => StatusCode::LOCKED,
+            Error::ServiceNotAllowed(_) | Error::Forbidden => StatusCode::FORBIDDEN,
+            Error::Shared(source) => source.status(),
+        }
+    }
 }
 
 impl axum::response::IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
         eprintln!("Error: `{self:?}`");
-        let status = match self {
-            Error::Io(_) | Error::PullFailed { .. } | Error::PruneFailed { .. } => {
-                StatusCode::INTERNAL_SERVER_ERROR
-            }
-            Error::Unauthorized => StatusCode::UNAUTHORIZED,
-            Error::NoComposition(_) => StatusCode::NOT_FOUND,
-        };
-        (status, self.to_string()).into_response()
+        let status = self.status();
+        let mut body = self.to_string();
+        if let Some(stderr) = self.stderr().filter(|s| !s.trim().is_empty()) {
+            body.push_str(process::tail(stderr, STDERR_TAIL_LINES, STDERR_TAIL_BYTES));
+            body.push('\n');
+        }
+        (status, body).into_response()
     }
 }
@@ -0,0 +1,202 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use crate::{deploy, freeze, AppState, Config};
+
+/// Check that every `depends_on` names a composition, and that no composition
+/// ends up depending on itself.
+pub fn validate(config: &Config) -> Result<(), String> {
+    for (name, composition) in &config.extra {
+        for dependency in &composition.depends_on {
+            if !config.extra.contains_key(dependency) {
+                return Err(format!(
+                    "{name} depends on unknown composition {dependency}"
+                ));
+            }
+        }
+    }
+    // Kahn's algorithm: whatever never runs out of dependencies is in a cycle
+    let mut waiting: BTreeMap<&str, usize> = config
+        .extra
+        .iter()
+        .map(|(name, composition)| (name.as_str(), composition.depends_on.len()))
+        .collect();
+    let mut ready: Vec<&str> = waiting
+        .iter()
+        .filter(|(_, count)| **count == 0)
+        .map(|(name, _)| *name)
+        .collect();
+    while let Some(done) = ready.pop() {
+        waiting.remove(done);
+        for (name, composition) in &config.extra {
+            if composition.depends_on.contains(done) {
+                let count = waiting
+                    .get_mut(name.as_str())
+                    .expect("cycle check lost a node");
+                *count -= 1;
+                if *count == 0 {
+                    ready.push(name);
+                }
+            }
+        }
+    }
+    if waiting.is_empty() {
+        Ok(())
+    } else {
+        let cycle: Vec<&str> = waiting.into_keys().collect();
+        Err(format!("dependency cycle between {}", cycle.join(", ")))
+    }
+}
+
+/// Restart `names`, each after whichever of its dependencies are also in
+/// `names`, with at most `config.concurrency` restarts at once. Returns the
+/// ones that restarted successfully.
+///
+/// Those held back by a freeze or outside their windows are queued to restart
+/// once they may, without waiting for them or taking up a permit meanwhile.
+/// Since that breaks the order, their dependents are skipped this time.
+pub async fn restart(names: BTreeSet<String>, state: Arc<AppState>) -> BTreeSet<String> {
+    let config = &state.config;
+    let mut waiting: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut dependents: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for name in &names {
+        let dependencies = config.extra[name]
+            .depends_on
+            .iter()
+            .filter(|dependency| names.contains(*dependency));
+        waiting.insert(name, 0);
+        for dependency in dependencies {
+            *waiting.entry(name).or_default() += 1;
+            dependents.entry(dependency).or_default().push(name);
+        }
+    }
+
+    let permits = Arc::new(Semaphore::new(config.concurrency.max(1)));
+    let mut running = JoinSet::new();
+    let mut restarted = BTreeSet::new();
+    let start = |name: &str, running: &mut JoinSet<_>| {
+        let (name, state, permits) = (name.to_owned(), state.clone(), permits.clone());
+        running.spawn(async move {
+            match freeze::defer(&name, &state) {
+                Ok(Some(blocked)) => {
+                    println!("[{name}] Deferred: {blocked}");
+                    return (name, None);
+                }
+                Ok(None) => {}
+                Err(source) => return (name, Some(Err(source))),
+            }
+            let _permit = permits.acquire().await;
+            let outcome = deploy(&name, state).await;
+            (name, Some(outcome))
+        });
+    };
+    let ready: Vec<&str> = waiting
+        .iter()
+        .filter(|(_, count)| **count == 0)
+        .map(|(name, _)| *name)
+        .collect();
+    for name in ready {
+        waiting.remove(name);
+        start(name, &mut running);
+    }
+
+    while let Some(finished) = running.join_next().await {
+        let Ok((name, outcome)) = finished else {
+            continue;
+        };
+        match &outcome {
+            Some(Ok(_)) => {
+                restarted.insert(name.clone());
+            }
+            Some(Err(source)) => {
+                eprintln!("Error: {source:?}");
+                if config.halt_dependents {
+                    continue;
+                }
+            }
+            None => continue,
+        }
+        for dependent in dependents.get(name.as_str()).into_iter().flatten() {
+            let Some(count) = waiting.get_mut(dependent) else {
+                continue;
+            };
+            *count -= 1;
+            if *count == 0 {
+                waiting.remove(dependent);
+                start(dependent, &mut running);
+            }
+        }
+    }
+    for name in waiting.keys() {
+        eprintln!("Skipped restarting {name}, because a dependency failed or was deferred");
+    }
+    restarted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(compositions: &str) -> Config {
+        toml::from_str(compositions).unwrap()
+    }
+
+    #[test]
+    fn dependencies_in_order_are_fine() {
+        let config = config(
+            r#"
+[db]
+work = "/srv/db"
+[api]
+work = "/srv/api"
+depends_on = ["db"]
+[web]
+work = "/srv/web"
+depends_on = ["api", "db", "db"]
+"#,
+        );
+        assert_eq!(validate(&config), Ok(()));
+    }
+
+    #[test]
+    fn unknown_dependencies_are_rejected() {
+        let config = config(
+            r#"
+[web]
+work = "/srv/web"
+depends_on = ["api"]
+"#,
+        );
+        assert_eq!(
+            validate(&config),
+            Err("web depends on unknown composition api".to_owned())
+        );
+    }
+
+    #[test]
+    fn cycles_are_rejected() {
+        let config = config(
+            r#"
+[db]
+work = "/srv/db"
+[api]
+work = "/srv/api"
+depends_on = ["web", "db"]
+[web]
+work = "/srv/web"
+depends_on = ["api"]
+[solo]
+work = "/srv/solo"
+depends_on = ["solo"]
+"#,
+        );
+        assert_eq!(
+            validate(&config),
+            Err("dependency cycle between api, solo, web".to_owned())
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use futures_util::future::join_all;
+use tokio::net::TcpStream;
+
+use crate::{compose, runtime::Runtime, ManagedComposition};
+
+/// How long a single probe attempt may take, and how long to wait between them.
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A readiness check that must pass before a restart counts as a success.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+pub enum Probe {
+    Http {
+        http: String,
+        #[serde(default = "default_status")]
+        status: u16,
+    },
+    Tcp {
+        tcp: String,
+    },
+    Exec {
+        service: String,
+        command: Vec<String>,
+    },
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+impl Display for Probe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Http { http, .. } => write!(f, "GET {http}"),
+            Self::Tcp { tcp } => write!(f, "tcp {tcp}"),
+            Self::Exec { service, command } => write!(f, "exec {service}: {}", command.join(" ")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ProbeResult {
+    pub probe: String,
+    pub passed: bool,
+    pub detail: String,
+    pub elapsed: Duration,
+}
+
+impl Display for ProbeResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let verdict = if self.passed { "passed" } else { "failed" };
+        write!(
+            f,
+            "{}: {verdict} after {:.1}s ({})",
+            self.probe,
+            self.elapsed.as_secs_f64(),
+            self.detail
+        )
+    }
+}
+
+/// Retry every probe until it passes or `deadline` runs out.
+pub async fn run_all(
+    name: &str,
+    probes: &[Probe],
+    deadline: Duration,
+    runtime: Runtime,
+    composition: &ManagedComposition,
+) -> Vec<ProbeResult> {
+    let client = reqwest::Client::new();
+    let checks = probes.iter().map(|probe| async {
+        let started = Instant::now();
+        loop {
+            let attempt =
+                tokio::time::timeout(ATTEMPT_TIMEOUT, check(probe, &client, runtime, composition))
+                    .await
+                    .unwrap_or_else(|_| Err("attempt timed out".to_owned()));
+            let elapsed = started.elapsed();
+            if attempt.is_ok() || elapsed >= deadline {
+                let passed = attempt.is_ok();
+                let detail = attempt.unwrap_or_else(|e| e);
+                let result = ProbeResult {
+                    probe: probe.to_string(),
+                    passed,
+                    detail,
+                    elapsed,
+                };
+                println!("[{name} probe] {result}");
+                return result;
+            }
+            tokio::time::sleep(RETRY_INTERVAL).await;
+        }
+    });
+    join_all(checks).await
+}
+
+async fn check(
+    probe: &Probe,
+    client: &reqwest::Client,
+    runtime: Runtime,
+    composition: &ManagedComposition,
+) -> Result<String, String> {
+    match probe {
+        Probe::Http { http, status } => {
+            let response = client.get(http).send().await.map_err(|e| e.to_string())?;
+            let got = response.status();
+            if got.as_u16() == *status {
+                Ok(got.to_string())
+            } else {
+                Err(format!("expected {status}, got {got}"))
+            }
+        }
+        Probe::Tcp { tcp } => match TcpStream::connect(tcp).await {
+            Ok(_) => Ok("connected".to_owned()),
+            Err(e) => Err(e.to_string()),
+        },
+        Probe::Exec { service, command } => {
+            let output = compose::command(runtime, composition)
+                .arg("exec")
+                .arg("-T")
+                .arg(service)
+                .args(command)
+                .kill_on_drop(true)
+                .output()
+                .await
+                .map_err(|e| e.to_string())?;
+            if output.status.success() {
+                Ok(output.status.to_string())
+            } else {
+                Err(runtime.parse_error(&String::from_utf8_lossy(&output.stderr)))
+            }
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::process::{ExitStatus, Stdio};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    process::Command,
+};
+
+use crate::{
+    queue::{Run, Stream},
+    Error,
+};
+
+/// Everything a finished child process wrote.
+pub struct Captured {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Run `command` to completion, logging its output line by line under `label`
+/// and recording it on `run` as it arrives.
+pub async fn capture(
+    mut command: Command,
+    label: &str,
+    run: Option<&Run>,
+) -> Result<Captured, Error> {
+    command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0);
+    let mut child = command.spawn()?;
+    let mut group = child.id().map(ProcessGroup);
+    let (stdout, stderr) = tokio::join!(
+        collect(child.stdout.take(), Stream::Stdout, label, run),
+        collect(child.stderr.take(), Stream::Stderr, label, run),
+    );
+    let status = child.wait().await?;
+    // The leader exited on its own, so leave whatever's left of the group be
+    std::mem::forget(group.take());
+    if let Some(run) = run {
+        run.set_exit_code(status.code());
+    }
+    Ok(Captured {
+        status,
+        stdout: stdout?,
+        stderr: stderr?,
+    })
+}
+
+/// Kills a whole process group when dropped, so that a restart that times out
+/// or is cancelled doesn't leave `docker compose` and its plugins behind.
+struct ProcessGroup(u32);
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        let Ok(pgid) = libc::pid_t::try_from(self.0) else {
+            return;
+        };
+        // SAFETY: kill has no memory safety preconditions
+        unsafe {
+            libc::kill(-pgid, libc::SIGKILL);
+        }
+    }
+}
+
+async fn collect<R>(
+    pipe: Option<R>,
+    stream: Stream,
+    label: &str,
+    run: Option<&Run>,
+) -> Result<String, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut all = String::new();
+    let Some(pipe) = pipe else {
+        return Ok(all);
+    };
+    let mut reader = BufReader::new(pipe);
+    let mut buf = Vec::new();
+    while reader.read_until(b'\n', &mut buf).await? != 0 {
+        let line = String::from_utf8_lossy(&buf);
+        let line = line.trim_end_matches(['\r', '\n']);
+        println!("[{label} {stream}] {line}");
+        all.push_str(line);
+        all.push('\n');
+        if let Some(run) = run {
+            run.push_line(stream, line.to_owned());
+        }
+        buf.clear();
+    }
+    Ok(all)
+}
+
+/// The end of some process output, small enough to put in an HTTP response.
+pub fn tail(output: &str, max_lines: usize, max_bytes: usize) -> &str {
+    let output = output.trim_end();
+    let start = output
+        .rmatch_indices('\n')
+        .nth(max_lines.saturating_sub(1))
+        .map_or(0, |(i, _)| i + 1);
+    let mut start = start.max(output.len().saturating_sub(max_bytes));
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    &output[start..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tail_keeps_the_last_lines() {
+        assert_eq!(tail("one\ntwo\nthree\n", 2, 100), "two\nthree");
+        assert_eq!(tail("one\ntwo\nthree", 5, 100), "one\ntwo\nthree");
+        assert_eq!(tail("one\ntwo\n\n\n", 1, 100), "two");
+        assert_eq!(tail("", 3, 100), "");
+    }
+
+    #[test]
+    fn tail_keeps_the_last_bytes() {
+        assert_eq!(tail("abcdef\nghij", 10, 6), "f\nghij");
+        assert_eq!(tail("abcdef", 10, 0), "");
+    }
+
+    #[test]
+    fn tail_never_splits_characters() {
+        // "é" is two bytes, so the cut moves forward past it
+        assert_eq!(tail("aébc", 1, 3), "bc");
+        assert_eq!(tail("aébc", 1, 4), "ébc");
+    }
+}
@@ -0,0 +1,217 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::StatusCode,
+    Extension,
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+
+use crate::{
+    auth::{self, ClientIdentity, Identity, Scope},
+    deploy, freeze,
+    image::ImageRef,
+    AppState, Error,
+};
+
+#[derive(serde::Deserialize)]
+pub struct TokenQuery {
+    token: Option<String>,
+}
+
+/// Docker Hub webhooks can't carry headers, so the token goes in `?token=`.
+pub async fn dockerhub_web(
+    State(state): State<Arc<AppState>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    client: Option<Extension<ClientIdentity>>,
+    Query(query): Query<TokenQuery>,
+    body: Bytes,
+) -> Result<(StatusCode, String), Error> {
+    let identity = authenticate(&state, auth, client, query).await?;
+    let event: DockerHubEvent = serde_json::from_slice(&body)?;
+    let image = ImageRef::from_parts(None, &event.repository.repo_name, &event.push_data.tag);
+    restart_consumers(&[image], &identity, state).await
+}
+
+/// Harbor sends its configured "Auth Header" verbatim, so set it to `Bearer <token>`.
+pub async fn harbor_web(
+    State(state): State<Arc<AppState>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    client: Option<Extension<ClientIdentity>>,
+    Query(query): Query<TokenQuery>,
+    body: Bytes,
+) -> Result<(StatusCode, String), Error> {
+    let identity = authenticate(&state, auth, client, query).await?;
+    let event: HarborEvent = serde_json::from_slice(&body)?;
+    if event.event_type != "PUSH_ARTIFACT" {
+        return Ok((StatusCode::OK, "Ignored\n".to_owned()));
+    }
+    let images: Vec<ImageRef> = event
+        .event_data
+        .resources
+        .iter()
+        .filter(|resource| resource.tag.is_some())
+        .map(|resource| ImageRef::parse(&resource.resource_url))
+        .collect();
+    restart_consumers(&images, &identity, state).await
+}
+
+/// Receives the envelope that `distribution` (`registry:2`) sends to its
+/// configured `notifications.endpoints`. Only tagged manifest pushes count.
+pub async fn distribution_web(
+    State(state): State<Arc<AppState>>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    client: Option<Extension<ClientIdentity>>,
+    Query(query): Query<TokenQuery>,
+    body: Bytes,
+) -> Result<(StatusCode, String), Error> {
+    let identity = authenticate(&state, auth, client, query).await?;
+    let envelope: DistributionEnvelope = serde_json::from_slice(&body)?;
+    let images: Vec<ImageRef> = envelope
+        .events
+        .iter()
+        .filter(|event| event.action == "push")
+        .filter_map(|event| {
+            let tag = event.target.tag.as_deref()?;
+            let host = event.request.as_ref().map(|r| r.host.as_str());
+            Some(ImageRef::from_parts(host, &event.target.repository, tag))
+        })
+        .collect();
+    restart_consumers(&images, &identity, state).await
+}
+
+async fn authenticate(
+    state: &AppState,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    client: Option<Extension<ClientIdentity>>,
+    query: TokenQuery,
+) -> Result<Arc<Identity>, Error> {
+    let token = match (&auth, &query.token) {
+        (Some(TypedHeader(Authorization(auth))), _) => Some(auth.token()),
+        (None, token) => token.as_deref(),
+    };
+    auth::identify(
+        state,
+        token,
+        client.as_ref().map(|Extension(client)| client),
+    )
+    .await
+}
+
+/// Restart every composition that declares it consumes one of `images`, and
+/// that `identity` may restart.
+async fn restart_consumers(
+    images: &[ImageRef],
+    identity: &Identity,
+    state: Arc<AppState>,
+) -> Result<(StatusCode, String), Error> {
+    let mut names: Vec<&String> = state
+        .config
+        .extra
+        .iter()
+        .filter(|(_, composition)| {
+            images
+                .iter()
+                .any(|image| composition.images.iter().any(|p| image.matches(p)))
+        })
+        .filter(|(name, _)| identity.check(Scope::Restart(name)).is_ok())
+        .map(|(name, _)| name)
+        .collect();
+    names.sort();
+    if names.is_empty() {
+        return Ok((StatusCode::OK, "Ignored\n".to_owned()));
+    }
+    let mut failure = None;
+    let (mut restarted, mut deferred) = (Vec::new(), Vec::new());
+    for name in names {
+        let outcome = match freeze::defer(name, &state) {
+            Ok(Some(blocked)) => {
+                deferred.push(format!("{name} ({blocked})"));
+                continue;
+            }
+            Ok(None) => deploy(name, state.clone()).await.map(drop),
+            Err(source) => Err(source),
+        };
+        match outcome {
+            Ok(()) => restarted.push(name.as_str()),
+            Err(source) => {
+                eprintln!("Error restarting {name}: {source:?}");
+                failure = Some(source);
+            }
+        }
+    }
+    if let Some(source) = failure {
+        return Err(source);
+    }
+    let mut body = String::new();
+    if !restarted.is_empty() {
+        body.push_str(&format!("Restarted {}\n", restarted.join(", ")));
+    }
+    if deferred.is_empty() {
+        return Ok((StatusCode::OK, body));
+    }
+    body.push_str(&format!("Deferred {}\n", deferred.join(", ")));
+    Ok((StatusCode::ACCEPTED, body))
+}
+
+#[derive(serde::Deserialize)]
+struct DockerHubEvent {
+    push_data: DockerHubPushData,
+    repository: DockerHubRepository,
+}
+
+#[derive(serde::Deserialize)]
+struct DockerHubPushData {
+    tag: String,
+}
+
+#[derive(serde::Deserialize)]
+struct DockerHubRepository {
+    repo_name: String,
+}
+
+#[derive(serde::Deserialize)]
+struct HarborEvent {
+    #[serde(rename = "type")]
+    event_type: String,
+    event_data: HarborEventData,
+}
+
+#[derive(serde::Deserialize)]
+struct HarborEventData {
+    #[serde(default)]
+    resources: Vec<HarborResource>,
+}
+
+#[derive(serde::Deserialize)]
+struct HarborResource {
+    tag: Option<String>,
+    resource_url: String,
+}
+
+#[derive(serde::Deserialize)]
+struct DistributionEnvelope {
+    events: Vec<DistributionEvent>,
+}
+
+#[derive(serde::Deserialize)]
+struct DistributionEvent {
+    action: String,
+    target: DistributionTarget,
+    request: Option<DistributionRequest>,
+}
+
+#[derive(serde::Deserialize)]
+struct DistributionTarget {
+    repository: String,
+    tag: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct DistributionRequest {
+    host: String,
+}
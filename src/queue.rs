@@ -0,0 +1,380 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use tokio::sync::{broadcast, watch};
+
+use crate::{Deployment, Error};
+
+pub type Outcome = Result<Arc<Deployment>, Arc<Error>>;
+
+/// How many output lines a slow log follower may fall behind by.
+const FOLLOW_CAPACITY: usize = 1024;
+
+/// Serializes restarts per composition.
+///
+/// A restart requested while another is running doesn't run in parallel. It
+/// waits for a single follow-up run, which every request for the same services
+/// arriving in the meantime shares.
+#[derive(Default)]
+pub struct Queues {
+    slots: Mutex<HashMap<String, Arc<Slot>>>,
+}
+
+#[derive(Default)]
+struct Slot {
+    running: tokio::sync::Mutex<()>,
+    /// The follow-up runs that new requests should join, by the service they
+    /// restart, or `None` for the whole composition
+    pending: Mutex<HashMap<Option<String>, Arc<Run>>>,
+    /// The run that is underway, or else the last one to finish
+    current: Mutex<Option<Arc<Run>>>,
+}
+
+impl Queues {
+    /// Queue `task` for `name`, or join a run of it for the same `service`
+    /// that is waiting to start.
+    ///
+    /// The run happens in its own task, so it completes even if every caller
+    /// goes away.
+    pub fn enqueue<F, Fut>(&self, name: &str, service: Option<&str>, task: F) -> Arc<Run>
+    where
+        F: FnOnce(Arc<Run>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<Deployment, Error>> + Send + 'static,
+    {
+        let slot = self.slot(name);
+        let key = service.map(ToOwned::to_owned);
+        let mut pending = lock(&slot.pending);
+        if let Some(run) = pending.get(&key) {
+            return run.clone();
+        }
+        let run = Arc::new(Run::new(name));
+        pending.insert(key.clone(), run.clone());
+        drop(pending);
+
+        let queued = run.clone();
+        tokio::spawn(async move {
+            let _running = tokio::select! {
+                running = slot.running.lock() => running,
+                () = queued.cancelled() => {
+                    slot.forget_pending(&key, &queued);
+                    queued.finish(Err(Arc::new(Error::Cancelled)));
+                    return;
+                }
+            };
+            // Anyone arriving from now on needs another run after this one
+            slot.forget_pending(&key, &queued);
+            *lock(&slot.current) = Some(queued.clone());
+            queued.start();
+            let mut task = tokio::spawn(task(queued.clone()));
+            let outcome = tokio::select! {
+                outcome = &mut task => outcome.unwrap_or(Err(Error::TaskFailed)),
+                () = queued.cancelled() => {
+                    // Dropping the restart kills any process group it started
+                    task.abort();
+                    Err(Error::Cancelled)
+                }
+            };
+            queued.finish(outcome.map(Arc::new).map_err(Arc::new));
+        });
+        run
+    }
+
+    /// The run most worth watching for `name`: the one underway, else the one
+    /// waiting to start, else the last one to finish.
+    pub fn latest(&self, name: &str) -> Option<Arc<Run>> {
+        let slot = lock(&self.slots).get(name)?.clone();
+        let current = lock(&slot.current).clone();
+        let pending = lock(&slot.pending).values().next().cloned();
+        match current {
+            Some(run) if run.progress().finished.is_none() => Some(run),
+            current => pending.or(current),
+        }
+    }
+
+    fn slot(&self, name: &str) -> Arc<Slot> {
+        lock(&self.slots)
+            .entry(name.to_owned())
+            .or_default()
+            .clone()
+    }
+}
+
+impl Slot {
+    /// Stop new requests from joining `run`, if they still would.
+    fn forget_pending(&self, key: &Option<String>, run: &Arc<Run>) {
+        let mut pending = lock(&self.pending);
+        if pending.get(key).is_some_and(|p| Arc::ptr_eq(p, run)) {
+            pending.remove(key);
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Clone, Copy)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl std::fmt::Display for Stream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stdout => f.write_str("stdout"),
+            Self::Stderr => f.write_str("stderr"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct OutputLine {
+    pub stream: Stream,
+    pub text: String,
+}
+
+/// A snapshot of how far a run has got.
+#[derive(Clone)]
+pub struct Progress {
+    pub status: Status,
+    pub started: Option<Instant>,
+    pub finished: Option<Instant>,
+    pub exit_code: Option<i32>,
+    pub output: Vec<OutputLine>,
+}
+
+/// One restart of a composition, shared by every request merged into it.
+pub struct Run {
+    composition: String,
+    progress: Mutex<Progress>,
+    outcome: watch::Sender<Option<Outcome>>,
+    /// New output lines, then `None` once the run has finished
+    follow: broadcast::Sender<Option<OutputLine>>,
+    cancel: watch::Sender<bool>,
+}
+
+impl Run {
+    fn new(composition: &str) -> Self {
+        Self {
+            composition: composition.to_owned(),
+            progress: Mutex::new(Progress {
+                status: Status::Queued,
+                started: None,
+                finished: None,
+                exit_code: None,
+                output: Vec::new(),
+            }),
+            outcome: watch::Sender::new(None),
+            follow: broadcast::Sender::new(FOLLOW_CAPACITY),
+            cancel: watch::Sender::new(false),
+        }
+    }
+
+    pub fn composition(&self) -> &str {
+        &self.composition
+    }
+
+    pub fn progress(&self) -> Progress {
+        lock(&self.progress).clone()
+    }
+
+    /// The outcome, once the run has finished.
+    pub fn outcome(&self) -> Option<Outcome> {
+        self.outcome.borrow().clone()
+    }
+
+    pub fn push_line(&self, stream: Stream, text: String) {
+        let line = OutputLine { stream, text };
+        let mut progress = lock(&self.progress);
+        // Sent under the lock, so followers see every line exactly once
+        self.follow.send(Some(line.clone())).ok();
+        progress.output.push(line);
+    }
+
+    /// The output so far, plus a receiver for the rest of it if the run
+    /// hasn't finished yet.
+    pub fn follow(
+        &self,
+    ) -> (
+        Vec<OutputLine>,
+        Option<broadcast::Receiver<Option<OutputLine>>>,
+    ) {
+        let progress = lock(&self.progress);
+        let receiver = progress.finished.is_none().then(|| self.follow.subscribe());
+        (progress.output.clone(), receiver)
+    }
+
+    pub fn set_exit_code(&self, exit_code: Option<i32>) {
+        lock(&self.progress).exit_code = exit_code;
+    }
+
+    /// Ask the run to stop, killing whatever it is running. Returns false if
+    /// it had already finished.
+    pub fn cancel(&self) -> bool {
+        if lock(&self.progress).finished.is_some() {
+            return false;
+        }
+        self.cancel.send_replace(true);
+        true
+    }
+
+    async fn cancelled(&self) {
+        let mut receiver = self.cancel.subscribe();
+        // We hold the sender, so this can't fail
+        receiver.wait_for(|cancelled| *cancelled).await.ok();
+    }
+
+    fn start(&self) {
+        let mut progress = lock(&self.progress);
+        progress.status = Status::Running;
+        progress.started = Some(Instant::now());
+    }
+
+    fn finish(&self, outcome: Outcome) {
+        let mut progress = lock(&self.progress);
+        progress.finished = Some(Instant::now());
+        progress.status = match outcome {
+            Ok(_) => Status::Succeeded,
+            Err(_) => Status::Failed,
+        };
+        drop(progress);
+        self.outcome.send_replace(Some(outcome));
+        self.follow.send(None).ok();
+    }
+
+    pub async fn wait(&self) -> Result<Arc<Deployment>, Error> {
+        let mut receiver = self.outcome.subscribe();
+        let outcome = receiver
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|_| Error::TaskFailed)?
+            .clone();
+        match outcome {
+            Some(Ok(deployment)) => Ok(deployment),
+            Some(Err(source)) => Err(Error::Shared(source)),
+            None => Err(Error::TaskFailed),
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    };
+
+    use tokio::sync::Notify;
+
+    use super::*;
+
+    type Task = Pin<Box<dyn Future<Output = Result<Deployment, Error>> + Send>>;
+
+    /// Restarts that each hold until released, counting how many ran and
+    /// whether any two were ever underway at once.
+    #[derive(Default)]
+    struct Gate {
+        started: Notify,
+        release: Notify,
+        running: AtomicUsize,
+        runs: AtomicUsize,
+        overlapped: AtomicBool,
+    }
+
+    impl Gate {
+        fn task(self: &Arc<Self>) -> impl FnOnce(Arc<Run>) -> Task {
+            let gate = self.clone();
+            move |_| {
+                Box::pin(async move {
+                    if gate.running.fetch_add(1, Ordering::SeqCst) > 0 {
+                        gate.overlapped.store(true, Ordering::SeqCst);
+                    }
+                    gate.runs.fetch_add(1, Ordering::SeqCst);
+                    gate.started.notify_one();
+                    gate.release.notified().await;
+                    gate.running.fetch_sub(1, Ordering::SeqCst);
+                    Ok(Deployment::default())
+                })
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_during_a_run_share_one_follow_up() {
+        let (queues, gate) = (Queues::default(), Arc::new(Gate::default()));
+        let first = queues.enqueue("web", None, gate.task());
+        gate.started.notified().await;
+        let second = queues.enqueue("web", None, gate.task());
+        let third = queues.enqueue("web", None, gate.task());
+        assert!(Arc::ptr_eq(&second, &third));
+        assert!(!Arc::ptr_eq(&first, &second));
+
+        gate.release.notify_one();
+        first.wait().await.unwrap();
+        gate.started.notified().await;
+        gate.release.notify_one();
+        third.wait().await.unwrap();
+        assert_eq!(gate.runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn runs_of_a_composition_never_overlap() {
+        let (queues, gate) = (Queues::default(), Arc::new(Gate::default()));
+        let whole = queues.enqueue("web", None, gate.task());
+        gate.started.notified().await;
+        // Restarting one service doesn't join the whole composition's run,
+        // but still waits for it, while other compositions go ahead
+        let service = queues.enqueue("web", Some("app"), gate.task());
+        let other = Arc::new(Gate::default());
+        let elsewhere = queues.enqueue("db", None, other.task());
+        other.started.notified().await;
+        assert!(service.progress().status == Status::Queued);
+
+        gate.release.notify_one();
+        whole.wait().await.unwrap();
+        gate.started.notified().await;
+        gate.release.notify_one();
+        service.wait().await.unwrap();
+        other.release.notify_one();
+        elsewhere.wait().await.unwrap();
+        assert_eq!(gate.runs.load(Ordering::SeqCst), 2);
+        assert!(!gate.overlapped.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn cancelled_runs_stop_waiting() {
+        let (queues, gate) = (Queues::default(), Arc::new(Gate::default()));
+        let first = queues.enqueue("web", None, gate.task());
+        gate.started.notified().await;
+        let second = queues.enqueue("web", None, gate.task());
+        assert!(second.cancel());
+        let Err(Error::Shared(source)) = second.wait().await else {
+            panic!("expected the run to be cancelled");
+        };
+        assert!(matches!(*source, Error::Cancelled));
+        assert!(lock(&queues.slot("web").pending).is_empty());
+        let third = queues.enqueue("web", None, gate.task());
+        assert!(!Arc::ptr_eq(&second, &third));
+
+        gate.release.notify_one();
+        first.wait().await.unwrap();
+        gate.started.notified().await;
+        gate.release.notify_one();
+        third.wait().await.unwrap();
+        assert_eq!(gate.runs.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,431 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use reqwest::{header, Method, RequestBuilder, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use tokio::{select, time::MissedTickBehavior};
+
+use crate::{
+    compose::Project,
+    docker_config::{self, Credentials},
+    engine::Engine,
+    image::ImageRef,
+    order, tags, AppState, Error,
+};
+
+/// Every manifest type a tag can point at, so the registry doesn't convert
+/// one to another and hand back a different digest.
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+/// How many pages of tags to read before giving up on a repository.
+const MAX_TAG_PAGES: usize = 100;
+
+/// Asks registries which manifest a tag points at, over the v2 API.
+pub struct Registries {
+    client: reqwest::Client,
+    /// Registries spoken to over plain HTTP, like a local `registry:2`
+    insecure: Vec<String>,
+}
+
+impl Registries {
+    pub fn new(insecure: &[String]) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            insecure: insecure.to_vec(),
+        }
+    }
+
+    /// The digest `image`'s tag currently points at.
+    pub async fn digest(&self, image: &ImageRef) -> Result<String, Error> {
+        let url = format!("{}/manifests/{}", self.base_url(image), image.tag);
+        let response = self
+            .fetch(Method::HEAD, &url, MANIFEST_TYPES, image)
+            .await?;
+        if let Some(digest) = response
+            .headers()
+            .get("docker-content-digest")
+            .and_then(|value| value.to_str().ok())
+        {
+            return Ok(digest.to_owned());
+        }
+        // Not every registry sends the digest, but it's just the manifest's hash
+        let response = self.fetch(Method::GET, &url, MANIFEST_TYPES, image).await?;
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| registry_error(image, describe(&e)))?;
+        Ok(format!("sha256:{}", hex::encode(Sha256::digest(&body))))
+    }
+
+    /// Every tag in `image`'s repository.
+    pub async fn tags(&self, image: &ImageRef) -> Result<Vec<String>, Error> {
+        let base = self.base_url(image);
+        let mut url = format!("{base}/tags/list?n=1000");
+        let mut tags = Vec::new();
+        for _ in 0..MAX_TAG_PAGES {
+            let response = self
+                .fetch(Method::GET, &url, "application/json", image)
+                .await?;
+            let next = response
+                .headers()
+                .get(header::LINK)
+                .and_then(|value| value.to_str().ok())
+                .and_then(next_link)
+                .map(ToOwned::to_owned);
+            let list: TagList = response
+                .json()
+                .await
+                .map_err(|e| registry_error(image, describe(&e)))?;
+            tags.extend(list.tags.unwrap_or_default());
+            match next {
+                // Links are relative to the registry, like `/v2/app/tags/list?last=1.4`
+                Some(next) => url = format!("{}{next}", origin(&base)),
+                None => break,
+            }
+        }
+        Ok(tags)
+    }
+
+    /// Where `image`'s repository lives, like `https://ghcr.io/v2/me/app`.
+    fn base_url(&self, image: &ImageRef) -> String {
+        let host = match image.registry.as_str() {
+            "docker.io" => "registry-1.docker.io",
+            other => other,
+        };
+        let scheme = if self.insecure.contains(&image.registry) {
+            "http"
+        } else {
+            "https"
+        };
+        format!("{scheme}://{host}/v2/{}", image.repository)
+    }
+
+    /// Send a request, answering the registry's auth challenge if it has one,
+    /// and fail unless it succeeds.
+    async fn fetch(
+        &self,
+        method: Method,
+        url: &str,
+        accept: &str,
+        image: &ImageRef,
+    ) -> Result<Response, Error> {
+        let request = || {
+            self.client
+                .request(method.clone(), url)
+                .header(header::ACCEPT, accept)
+        };
+        let mut response = send(request(), image).await?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            let challenge = response
+                .headers()
+                .get(header::WWW_AUTHENTICATE)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| registry_error(image, "401 without a WWW-Authenticate challenge"))?;
+            let authorization = self.authorize(challenge, image).await?;
+            response = send(
+                request().header(header::AUTHORIZATION, authorization),
+                image,
+            )
+            .await?;
+        }
+        if !response.status().is_success() {
+            return Err(registry_error(image, response.status()));
+        }
+        Ok(response)
+    }
+
+    /// Answer a `WWW-Authenticate` challenge, with credentials from
+    /// `docker login` if there are any.
+    async fn authorize(&self, challenge: &str, image: &ImageRef) -> Result<String, Error> {
+        let fail = |message: &str| registry_error(image, message);
+        let credentials = docker_config::credentials(&image.registry).await;
+        let (scheme, params) = challenge.split_once(' ').unwrap_or((challenge, ""));
+        if scheme.eq_ignore_ascii_case("basic") {
+            let Some(Credentials::Password { username, password }) = credentials else {
+                return Err(fail("registry wants a username and password"));
+            };
+            let basic = format!("{username}:{password}");
+            return Ok(format!("Basic {}", STANDARD.encode(basic)));
+        }
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return Err(registry_error(
+                image,
+                format!("unsupported auth scheme {scheme}"),
+            ));
+        }
+        let params = challenge_params(params);
+        let realm = params
+            .get("realm")
+            .ok_or_else(|| fail("bearer challenge without a realm"))?;
+        let scope = format!("repository:{}:pull", image.repository);
+        let mut query = vec![("scope", params.get("scope").copied().unwrap_or(&scope))];
+        if let Some(service) = params.get("service") {
+            query.push(("service", service));
+        }
+        let request = match credentials {
+            None => self.client.get(*realm).query(&query),
+            Some(Credentials::Password { username, password }) => self
+                .client
+                .get(*realm)
+                .query(&query)
+                .basic_auth(username, Some(password)),
+            // The OAuth2 flow, which only answers with an `access_token`
+            Some(Credentials::IdentityToken(token)) => {
+                query.extend([
+                    ("grant_type", "refresh_token"),
+                    ("refresh_token", &token),
+                    ("client_id", "conductor"),
+                ]);
+                self.client.post(*realm).form(&query)
+            }
+        };
+        let token: TokenResponse = request
+            .send()
+            .await
+            .and_then(Response::error_for_status)
+            .map_err(|e| registry_error(image, describe(&e)))?
+            .json()
+            .await
+            .map_err(|e| registry_error(image, describe(&e)))?;
+        let token = token
+            .token
+            .or(token.access_token)
+            .ok_or_else(|| fail("token endpoint returned no token"))?;
+        Ok(format!("Bearer {token}"))
+    }
+}
+
+async fn send(request: RequestBuilder, image: &ImageRef) -> Result<Response, Error> {
+    request
+        .send()
+        .await
+        .map_err(|e| registry_error(image, describe(&e)))
+}
+
+fn registry_error(image: &ImageRef, message: impl ToString) -> Error {
+    Error::Registry {
+        image: image.to_string(),
+        message: message.to_string(),
+    }
+}
+
+/// The target of a `Link: </v2/app/tags/list?last=b&n=1000>; rel="next"` header.
+fn next_link(link: &str) -> Option<&str> {
+    link.split(',')
+        .find(|part| part.contains("rel=\"next\""))
+        .and_then(|part| part.split_once('<'))
+        .and_then(|(_, rest)| rest.split_once('>'))
+        .map(|(target, _)| target)
+}
+
+/// `https://ghcr.io` from `https://ghcr.io/v2/me/app`.
+fn origin(url: &str) -> &str {
+    let host_start = url.find("://").map_or(0, |i| i + 3);
+    match url[host_start..].find('/') {
+        Some(path_start) => &url[..host_start + path_start],
+        None => url,
+    }
+}
+
+/// reqwest's errors leave out why, which is all in their sources.
+fn describe(error: &reqwest::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(error);
+    while let Some(error) = source {
+        let cause = error.to_string();
+        if !message.ends_with(&cause) {
+            message.push_str(": ");
+            message.push_str(&cause);
+        }
+        source = error.source();
+    }
+    message
+}
+
+#[derive(serde::Deserialize)]
+struct TagList {
+    tags: Option<Vec<String>>,
+}
+
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+/// Split `realm="https://auth.docker.io/token",service="registry.docker.io"`
+/// into its parameters. Quoted values may contain commas.
+fn challenge_params(params: &str) -> HashMap<&str, &str> {
+    let mut found = HashMap::new();
+    let mut rest = params.trim();
+    while let Some((key, after)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim();
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => after.split_once(',').unwrap_or((after, "")),
+        };
+        found.insert(key, value);
+        rest = after.trim_start_matches([',', ' ']);
+    }
+    found
+}
+
+/// Every `secs`, check the images of compositions with `poll` set, and restart
+/// those whose tags point somewhere other than what was last deployed: the
+/// images the engine has, or for runtimes conductor can't ask, the digests
+/// seen at the last successful restart. Services with tag tracking are moved
+/// to newer tags, and their compositions restarted until one succeeds.
+pub async fn poll(secs: u64, state: Arc<AppState>) {
+    let registries = Registries::new(&state.config.insecure_registries);
+    let mut deployed: HashMap<(String, ImageRef), String> = HashMap::new();
+    // Compositions whose env files were moved to new tags, until they restart
+    let mut retagged = BTreeSet::new();
+    let mut ticker = tokio::time::interval(Duration::from_secs(secs));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = ticker.tick() => {}
+        }
+        let mut changed = retagged.clone();
+        // Digests to remember as deployed once their composition restarts
+        let mut deploying = Vec::new();
+        for (name, composition) in &state.config.extra {
+            if !composition.poll && composition.tags.is_empty() {
+                continue;
+            }
+            let runtime = state.config.runtime_for(composition);
+            let project = match Project::load(runtime, composition).await {
+                Ok(project) => project,
+                Err(source) => {
+                    eprintln!("Error: {source:?}");
+                    continue;
+                }
+            };
+            for (service, tracking) in &composition.tags {
+                match tags::advance(&registries, composition, &project, service, tracking).await {
+                    Ok(Some((old, new))) => {
+                        println!("[{name} tags] {service}: {old} -> {new}");
+                        retagged.insert(name.clone());
+                        changed.insert(name.clone());
+                    }
+                    Ok(None) => {}
+                    Err(source) => eprintln!("Error: {source:?}"),
+                }
+            }
+            if !composition.poll {
+                continue;
+            }
+            for image in project.pullable_images() {
+                // Images pinned by digest never point anywhere else
+                if image.digest.is_some() {
+                    continue;
+                }
+                let digest = match registries.digest(&image).await {
+                    Ok(digest) => digest,
+                    Err(source) => {
+                        eprintln!("Error: {source:?}");
+                        continue;
+                    }
+                };
+                let key = (name.clone(), image.clone());
+                let current = if runtime.pulls_with_engine() {
+                    match local_digests(&image).await {
+                        Ok(digests) => digests,
+                        Err(source) => {
+                            eprintln!("Error: {source:?}");
+                            continue;
+                        }
+                    }
+                } else {
+                    deployed.get(&key).cloned().into_iter().collect()
+                };
+                if !current.contains(&digest) {
+                    let current = current.first().map_or("nothing", String::as_str);
+                    println!("[{name} poll] {image}: {current} -> {digest}");
+                    changed.insert(name.clone());
+                    deploying.push((key, digest));
+                }
+            }
+        }
+        if changed.is_empty() {
+            continue;
+        }
+        // Whatever failed or was held back by a freeze gets retried next time
+        let restarted = order::restart(changed, state.clone()).await;
+        retagged.retain(|name| !restarted.contains(name));
+        for (key, digest) in deploying {
+            if restarted.contains(&key.0) {
+                deployed.insert(key, digest);
+            }
+        }
+    }
+}
+
+/// The digests the engine's copy of `image` was pulled by, if it has one.
+async fn local_digests(image: &ImageRef) -> Result<Vec<String>, Error> {
+    match Engine::from_env()?.inspect_image(&image.to_string()).await {
+        Ok(inspect) => Ok(inspect
+            .repo_digests
+            .iter()
+            .filter_map(|reference| reference.split_once('@'))
+            .map(|(_, digest)| digest.to_owned())
+            .collect()),
+        Err(Error::Engine { status: 404, .. }) => Ok(Vec::new()),
+        Err(source) => Err(source),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenge_params_are_split() {
+        let params = challenge_params(
+            r#"realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/nginx:pull""#,
+        );
+        assert_eq!(params["realm"], "https://auth.docker.io/token");
+        assert_eq!(params["service"], "registry.docker.io");
+        assert_eq!(params["scope"], "repository:library/nginx:pull");
+    }
+
+    #[test]
+    fn challenge_params_keep_commas_in_quotes() {
+        let params = challenge_params(
+            r#"realm="https://ghcr.io/token", scope="repository:a:pull,push", error=invalid_token"#,
+        );
+        assert_eq!(params["realm"], "https://ghcr.io/token");
+        assert_eq!(params["scope"], "repository:a:pull,push");
+        assert_eq!(params["error"], "invalid_token");
+        assert!(challenge_params("").is_empty());
+    }
+
+    #[test]
+    fn next_link_finds_the_next_page() {
+        assert_eq!(
+            next_link(r#"</v2/app/tags/list?last=1.4&n=1000>; rel="next""#),
+            Some("/v2/app/tags/list?last=1.4&n=1000")
+        );
+        assert_eq!(
+            next_link(
+                r#"</v2/app/tags/list?n=1000>; rel="prev", </v2/app/tags/list?last=b>; rel="next""#
+            ),
+            Some("/v2/app/tags/list?last=b")
+        );
+        assert_eq!(next_link(r#"</v2/app/tags/list?n=1000>; rel="prev""#), None);
+    }
+
+    #[test]
+    fn origin_drops_the_path() {
+        assert_eq!(origin("https://ghcr.io/v2/me/app"), "https://ghcr.io");
+        assert_eq!(origin("http://localhost:5000"), "http://localhost:5000");
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    compose::{self, Project},
+    engine::{ContainerEvent, Engine},
+    image::ImageRef,
+    queue::Run,
+    runtime::Runtime,
+    Config, Error, ManagedComposition,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// When set, a restart waits for the new containers to become healthy and
+/// puts the previous images back if they don't.
+#[derive(serde::Deserialize)]
+pub struct RollbackSettings {
+    /// Seconds to wait for every container to become healthy
+    #[serde(default = "default_health_timeout")]
+    health_timeout: u64,
+    /// Seconds a container without a healthcheck must stay up to count as healthy
+    #[serde(default = "default_stable_for")]
+    stable_for: u64,
+}
+
+fn default_health_timeout() -> u64 {
+    120
+}
+
+fn default_stable_for() -> u64 {
+    30
+}
+
+/// The image each service was running at some point, and the configuration
+/// compose created its container from.
+pub struct Snapshot {
+    images: HashMap<String, String>,
+    config_hashes: HashMap<String, String>,
+}
+
+impl Snapshot {
+    pub async fn take(engine: &Engine, project: &str) -> Result<Self, Error> {
+        let (mut images, mut config_hashes) = (HashMap::new(), HashMap::new());
+        for container in engine.project_containers(project).await? {
+            let Some(service) = container.service().filter(|_| container.state == "running") else {
+                continue;
+            };
+            let service = service.to_owned();
+            if let Some(hash) = container.labels.get("com.docker.compose.config-hash") {
+                config_hashes.insert(service.clone(), hash.clone());
+            }
+            images.insert(service, container.image_id);
+        }
+        Ok(Self {
+            images,
+            config_hashes,
+        })
+    }
+
+    /// The ID of the image `service` was running.
+    pub fn image(&self, service: &str) -> Option<&str> {
+        self.images.get(service).map(String::as_str)
+    }
+
+    /// The hash of the configuration `service`'s container was created from.
+    pub fn config_hash(&self, service: &str) -> Option<&str> {
+        self.config_hashes.get(service).map(String::as_str)
+    }
+}
+
+/// Check that rollback is only set where there's an engine to roll back with.
+pub fn validate(config: &Config) -> Result<(), String> {
+    for (name, composition) in &config.extra {
+        let runtime = config.runtime_for(composition);
+        if composition.rollback.is_some() && !runtime.pulls_with_engine() {
+            return Err(format!(
+                "{name} has rollback, which the {runtime} runtime doesn't support"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Wait for every container `events` show the deploy creating to be healthy:
+/// passing its healthcheck if it has one, and otherwise running without
+/// restarting for `stable_for`. Returns the services that never got there.
+/// Containers the deploy left alone aren't its problem.
+pub async fn wait_healthy(
+    engine: &Engine,
+    project: &str,
+    events: &[ContainerEvent],
+    settings: &RollbackSettings,
+) -> Result<Vec<String>, Error> {
+    let created: HashSet<&str> = events
+        .iter()
+        .filter(|event| event.action == "create")
+        .map(|event| event.actor.id.as_str())
+        .collect();
+    let deadline = Instant::now() + Duration::from_secs(settings.health_timeout);
+    let stable_for = Duration::from_secs(settings.stable_for);
+    // When we first saw each container running since it last (re)started
+    let mut up_since: HashMap<String, (String, Instant)> = HashMap::new();
+    loop {
+        let now = Instant::now();
+        let mut unhealthy = BTreeSet::new();
+        for container in engine.project_containers(project).await? {
+            let Some(service) = container.service() else {
+                continue;
+            };
+            if !created.contains(container.id.as_str()) {
+                continue;
+            }
+            if container
+                .labels
+                .get("com.docker.compose.oneoff")
+                .is_some_and(|oneoff| oneoff == "True")
+            {
+                continue;
+            }
+            let state = engine.inspect_container(&container.id).await?.state;
+            let healthy = match &state.health {
+                Some(health) => health.status == "healthy",
+                // One-shot jobs like migrations are done, not broken
+                None if state.status == "exited" => state.exit_code == 0,
+                None if state.status == "running" => {
+                    let (started_at, since) = up_since
+                        .entry(container.id.clone())
+                        .or_insert_with(|| (state.started_at.clone(), now));
+                    if *started_at != state.started_at {
+                        *started_at = state.started_at.clone();
+                        *since = now;
+                    }
+                    now.duration_since(*since) >= stable_for
+                }
+                None => false,
+            };
+            if !healthy {
+                unhealthy.insert(service.to_owned());
+            }
+        }
+        if unhealthy.is_empty() || now >= deadline {
+            return Ok(unhealthy.into_iter().collect());
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Point every service whose image changed since `before` back at its old
+/// image, and recreate it. Returns the services that were rolled back.
+pub async fn roll_back(
+    engine: &Engine,
+    runtime: Runtime,
+    composition: &ManagedComposition,
+    project: &Project,
+    before: &Snapshot,
+    run: &Run,
+) -> Result<Vec<String>, Error> {
+    let after = Snapshot::take(engine, &project.name).await?;
+    let mut restored = Vec::new();
+    for (service, old_id) in &before.images {
+        if after.images.get(service) == Some(old_id) {
+            continue;
+        }
+        let Some(image) = project
+            .services
+            .get(service)
+            .and_then(|s| s.image.as_deref())
+        else {
+            continue;
+        };
+        let image = ImageRef::parse(image);
+        if image.digest.is_some() {
+            eprintln!("Can't roll back {service}, its image is pinned by digest");
+            continue;
+        }
+        eprintln!("Rolling back {service} to {old_id}");
+        engine.tag_image(old_id, &image).await?;
+        restored.push(service.clone());
+    }
+    restored.sort();
+    if !restored.is_empty() {
+        compose::run(runtime, composition, &["up", "-d", "--pull", "never"], run).await?;
+    }
+    Ok(restored)
+}
@@ -0,0 +1,158 @@
+use std::fmt::Display;
+
+use tokio::process::Command;
+
+/// The container runtime that manages a composition.
+///
+/// Docker is driven through its engine API wherever possible. The others only
+/// have their CLIs, so pulls and prunes are shelled out to them.
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Runtime {
+    #[default]
+    Docker,
+    Podman,
+    Nerdctl,
+    /// The standalone python `docker-compose` v1, talking to a docker engine
+    DockerCompose,
+}
+
+impl Runtime {
+    /// A command running this runtime's compose implementation.
+    pub fn compose(self) -> Command {
+        match self {
+            Self::Docker => subcommand("docker", "compose"),
+            Self::Podman => subcommand("podman", "compose"),
+            Self::Nerdctl => subcommand("nerdctl", "compose"),
+            Self::DockerCompose => Command::new("docker-compose"),
+        }
+    }
+
+    /// Whether images and containers live in a docker engine we can talk to.
+    pub fn uses_engine(self) -> bool {
+        matches!(self, Self::Docker | Self::DockerCompose)
+    }
+
+    /// Whether pulls can go through the engine. `docker-compose` v1 can't
+    /// describe its project as JSON, so it has to pull for itself.
+    pub fn pulls_with_engine(self) -> bool {
+        self == Self::Docker
+    }
+
+    /// A command removing every unused image, for runtimes without an engine API.
+    pub fn prune(self) -> Command {
+        let mut command = match self {
+            Self::Docker | Self::DockerCompose => subcommand("docker", "image"),
+            Self::Podman => subcommand("podman", "image"),
+            Self::Nerdctl => subcommand("nerdctl", "image"),
+        };
+        command.arg("prune").arg("-a").arg("-f");
+        command
+    }
+
+    /// Pick the line that explains a failure out of this runtime's stderr.
+    pub fn parse_error(self, stderr: &str) -> String {
+        let lines = stderr.lines().map(str::trim);
+        let found = match self {
+            Self::Docker => lines
+                .rev()
+                .find(|l| l.starts_with("Error") || l.contains("error from daemon"))
+                .map(ToOwned::to_owned),
+            Self::Podman => lines
+                .filter_map(|l| l.strip_prefix("Error: "))
+                .next_back()
+                .map(ToOwned::to_owned),
+            Self::Nerdctl => lines
+                .filter(|l| l.contains("level=fatal") || l.contains("level=error"))
+                .filter_map(|l| l.split_once("msg=").map(|(_, msg)| msg))
+                .next_back()
+                .map(|msg| {
+                    // Only the outer quotes, since the message may end in an escaped one
+                    let unquoted = msg.strip_prefix('"').and_then(|m| m.strip_suffix('"'));
+                    unquoted.unwrap_or(msg).replace("\\\"", "\"")
+                }),
+            Self::DockerCompose => lines
+                .filter_map(|l| l.strip_prefix("ERROR: "))
+                .next_back()
+                .map(ToOwned::to_owned),
+        };
+        found
+            .or_else(|| {
+                stderr
+                    .lines()
+                    .rev()
+                    .find(|l| !l.trim().is_empty())
+                    .map(ToOwned::to_owned)
+            })
+            .unwrap_or_else(|| "no error output".to_owned())
+    }
+}
+
+impl Display for Runtime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Docker => "docker",
+            Self::Podman => "podman",
+            Self::Nerdctl => "nerdctl",
+            Self::DockerCompose => "docker-compose",
+        };
+        f.write_str(name)
+    }
+}
+
+fn subcommand(program: &str, subcommand: &str) -> Command {
+    let mut command = Command::new(program);
+    command.arg(subcommand);
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn docker_errors_are_found() {
+        let stderr = " web Pulling \n Error response from daemon: manifest for app:9 not found\n";
+        assert_eq!(
+            Runtime::Docker.parse_error(stderr),
+            "Error response from daemon: manifest for app:9 not found"
+        );
+    }
+
+    #[test]
+    fn podman_errors_are_found() {
+        let stderr = "Trying to pull docker.io/library/app:9...\nError: initializing source: reading manifest 9\n";
+        assert_eq!(
+            Runtime::Podman.parse_error(stderr),
+            "initializing source: reading manifest 9"
+        );
+    }
+
+    #[test]
+    fn nerdctl_errors_are_unquoted() {
+        let stderr =
+            "time=\"2026-10-15T09:00:00Z\" level=fatal msg=\"failed to resolve \\\"app:9\\\"\"\n";
+        assert_eq!(
+            Runtime::Nerdctl.parse_error(stderr),
+            "failed to resolve \"app:9\""
+        );
+    }
+
+    #[test]
+    fn compose_v1_errors_are_found() {
+        let stderr = "Pulling web ...\nERROR: for web  manifest unknown\nERROR: manifest unknown\n";
+        assert_eq!(
+            Runtime::DockerCompose.parse_error(stderr),
+            "manifest unknown"
+        );
+    }
+
+    #[test]
+    fn otherwise_the_last_line_is_used() {
+        assert_eq!(
+            Runtime::Podman.parse_error("something broke\n\n  \n"),
+            "something broke"
+        );
+        assert_eq!(Runtime::Docker.parse_error(""), "no error output");
+    }
+}
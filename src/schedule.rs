@@ -0,0 +1,174 @@
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use chrono::Utc;
+use chrono_tz::Tz;
+use rand::Rng;
+use serde::{de::Error as _, Deserialize, Deserializer};
+use tokio::select;
+
+use crate::{deploy, AppState};
+
+/// Restart a composition on a cron schedule of its own, instead of with
+/// everything else on `force_update_interval`.
+#[derive(serde::Deserialize)]
+pub struct Schedule {
+    /// Standard five-field cron syntax, where 0 and 7 are Sunday, or the cron
+    /// crate's six fields starting with seconds, where 1 is Sunday
+    #[serde(deserialize_with = "deserialize_cron")]
+    cron: cron::Schedule,
+    /// IANA timezone the expression is in, like `Europe/Berlin`
+    #[serde(default = "default_timezone")]
+    timezone: Tz,
+    /// Up to this many seconds are randomly added to every firing
+    #[serde(default)]
+    jitter: u64,
+}
+
+fn default_timezone() -> Tz {
+    Tz::UTC
+}
+
+fn deserialize_cron<'de, D: Deserializer<'de>>(de: D) -> Result<cron::Schedule, D::Error> {
+    parse_cron(&String::deserialize(de)?).map_err(D::Error::custom)
+}
+
+const DAY_NAMES: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+fn parse_cron(expression: &str) -> Result<cron::Schedule, String> {
+    let fields: Vec<&str> = expression.split_whitespace().collect();
+    let expression = match fields.as_slice() {
+        // The cron crate wants a seconds field, which nobody writes, and
+        // counts days of the week from 1 for Sunday instead of 0
+        [minute, hour, day, month, weekday] => {
+            format!("0 {minute} {hour} {day} {month} {}", day_names(weekday)?)
+        }
+        _ => expression.to_owned(),
+    };
+    cron::Schedule::from_str(&expression).map_err(|e| e.to_string())
+}
+
+/// Spell out the numbers in a standard day-of-week field, like `1-5` or
+/// `0,6`, as day names. Names, `*` and `?` mean the same to both already.
+fn day_names(field: &str) -> Result<String, String> {
+    let invalid = || format!("invalid day of week {field}");
+    let mut items = Vec::new();
+    for item in field.split(',') {
+        if !item
+            .bytes()
+            .all(|b| b.is_ascii_digit() || b == b'-' || b == b'/')
+        {
+            items.push(item.to_owned());
+            continue;
+        }
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, Some(step.parse::<usize>().map_err(|_| invalid())?)),
+            None => (item, None),
+        };
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start, Some(end)),
+            None => (range, None),
+        };
+        let start: usize = start.parse().map_err(|_| invalid())?;
+        let end: usize = match (end, step) {
+            (Some(end), _) => end.parse().map_err(|_| invalid())?,
+            // `1/2` runs to the end of the week
+            (None, Some(_)) => 6,
+            (None, None) => start,
+        };
+        if end > 7 || start > end || step == Some(0) {
+            return Err(invalid());
+        }
+        for day in (start..=end).step_by(step.unwrap_or(1)) {
+            let name = DAY_NAMES[day % 7];
+            if !items.iter().any(|item| item == name) {
+                items.push(name.to_owned());
+            }
+        }
+    }
+    Ok(items.join(","))
+}
+
+impl Schedule {
+    /// How long to wait before the next firing, jitter included.
+    fn until_next(&self) -> Option<Duration> {
+        let next = self.cron.upcoming(self.timezone).next()?;
+        let wait = (next.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default();
+        let jitter = rand::thread_rng().gen_range(0..=self.jitter);
+        Some(wait + Duration::from_secs(jitter))
+    }
+}
+
+/// Restart `name` every time its schedule fires, until ctrl-c.
+pub async fn run(name: String, state: Arc<AppState>) {
+    let Some(schedule) = state.config.extra[&name].schedule.as_ref() else {
+        return;
+    };
+    while let Some(wait) = schedule.until_next() {
+        select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = tokio::time::sleep(wait) => {}
+        }
+        println!("Scheduled restart of {name}");
+        if let Err(source) = deploy(&name, state.clone()).await {
+            eprintln!("Error: {source:?}")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Datelike, TimeZone, Utc, Weekday};
+
+    use super::*;
+
+    /// The days `expression` fires on in the week from a Sunday midnight.
+    fn weekdays(expression: &str) -> Vec<Weekday> {
+        let schedule = parse_cron(expression).unwrap();
+        let start = Utc.with_ymd_and_hms(2026, 1, 4, 0, 0, 0).unwrap();
+        schedule
+            .after(&start)
+            .take_while(|time| *time < start + chrono::Duration::days(7))
+            .map(|time| time.weekday())
+            .collect()
+    }
+
+    #[test]
+    fn one_to_five_is_monday_to_friday() {
+        use Weekday::*;
+        assert_eq!(weekdays("0 4 * * 1-5"), [Mon, Tue, Wed, Thu, Fri]);
+    }
+
+    #[test]
+    fn zero_and_seven_are_sunday() {
+        assert_eq!(weekdays("0 4 * * 0")[0], Weekday::Sun);
+        assert_eq!(weekdays("0 4 * * 7")[0], Weekday::Sun);
+    }
+
+    #[test]
+    fn ranges_through_seven_end_on_sunday() {
+        use Weekday::*;
+        assert_eq!(weekdays("0 4 * * 5-7"), [Sun, Fri, Sat]);
+    }
+
+    #[test]
+    fn steps_and_lists() {
+        use Weekday::*;
+        assert_eq!(weekdays("0 4 * * 0-6/2"), [Sun, Tue, Thu, Sat]);
+        assert_eq!(weekdays("0 4 * * 1,3"), [Mon, Wed]);
+        assert_eq!(weekdays("0 4 * * Mon,Sat"), [Mon, Sat]);
+    }
+
+    #[test]
+    fn six_fields_are_left_alone() {
+        // The cron crate's own numbering, where 2 is Monday
+        assert_eq!(weekdays("0 0 4 * * 2"), [Weekday::Mon]);
+    }
+
+    #[test]
+    fn out_of_range_days_are_rejected() {
+        assert!(parse_cron("0 4 * * 8").is_err());
+        assert!(parse_cron("0 4 * * 5-2").is_err());
+    }
+}
@@ -0,0 +1,138 @@
+use std::path::{Path, PathBuf};
+
+use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
+use serde::{de::Error as _, Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+/// A secret written into the config, or read when the config is loaded from
+/// `{ file = "/path" }`, `{ credential = "name" }` (a systemd `LoadCredential`
+/// in `$CREDENTIALS_DIRECTORY`) or `{ env = "VARIABLE" }`.
+#[derive(Clone)]
+pub struct Secret(String);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Source {
+    Inline(String),
+    File { file: PathBuf },
+    Credential { credential: String },
+    Env { env: String },
+}
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    fn read(source: Source) -> Result<Self, String> {
+        match source {
+            Source::Inline(secret) => Ok(Self(secret)),
+            Source::File { file } => Self::read_file(&file),
+            Source::Credential { credential } => {
+                let directory = std::env::var_os("CREDENTIALS_DIRECTORY").ok_or_else(|| {
+                    format!("credential {credential} needs $CREDENTIALS_DIRECTORY")
+                })?;
+                Self::read_file(&Path::new(&directory).join(credential))
+            }
+            Source::Env { env } => std::env::var(&env)
+                .map(Self)
+                .map_err(|e| format!("${env}: {e}")),
+        }
+    }
+
+    /// Files usually end in a newline that isn't part of the secret.
+    fn read_file(path: &Path) -> Result<Self, String> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        Ok(Self(contents.trim_end_matches(['\r', '\n']).to_owned()))
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        Self::read(Source::deserialize(de)?).map_err(D::Error::custom)
+    }
+}
+
+/// For `token_file = "/path"`, the same as `token = { file = "/path" }`.
+pub fn from_file<'de, D: Deserializer<'de>>(de: D) -> Result<Option<Secret>, D::Error> {
+    let path = PathBuf::deserialize(de)?;
+    Secret::read_file(&path).map(Some).map_err(D::Error::custom)
+}
+
+/// What a presented token is checked against: the token itself, or its hash
+/// as `{ sha256 = "<hex>" }` or `{ argon2 = "$argon2id$..." }`.
+#[derive(Clone)]
+pub enum Token {
+    Sha256([u8; 32]),
+    /// A PHC string, which was checked when the config was read
+    Argon2(String),
+    Plain(Secret),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawToken {
+    Sha256 { sha256: String },
+    Argon2 { argon2: String },
+    Plain(Source),
+}
+
+impl<'de> Deserialize<'de> for Token {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        match RawToken::deserialize(de)? {
+            RawToken::Sha256 { sha256 } => hex::decode(sha256.trim())
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .map(Self::Sha256)
+                .ok_or_else(|| D::Error::custom("a sha256 hash is 64 hex digits")),
+            RawToken::Argon2 { argon2 } => match PasswordHash::new(&argon2) {
+                Ok(_) => Ok(Self::Argon2(argon2)),
+                Err(e) => Err(D::Error::custom(format!("invalid argon2 hash: {e}"))),
+            },
+            RawToken::Plain(source) => Secret::read(source)
+                .map(Self::Plain)
+                .map_err(D::Error::custom),
+        }
+    }
+}
+
+impl From<Secret> for Token {
+    fn from(secret: Secret) -> Self {
+        Self::Plain(secret)
+    }
+}
+
+impl Token {
+    /// Whether checking a token against this takes long enough that it
+    /// shouldn't hold up an async worker thread.
+    pub fn is_slow(&self) -> bool {
+        matches!(self, Self::Argon2(_))
+    }
+
+    /// Whether `presented` is this token, in time that depends on neither.
+    /// Both sides are hashed first, since comparing bytes of different
+    /// lengths would give away the length.
+    pub fn matches(&self, presented: &str) -> bool {
+        let presented_hash = Sha256::digest(presented.as_bytes());
+        match self {
+            Self::Sha256(sha256) => presented_hash.as_slice().ct_eq(sha256).into(),
+            Self::Plain(secret) => {
+                let hash = Sha256::digest(secret.expose().as_bytes());
+                presented_hash.as_slice().ct_eq(hash.as_slice()).into()
+            }
+            Self::Argon2(argon2) => PasswordHash::new(argon2).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(presented.as_bytes(), &hash)
+                    .is_ok()
+            }),
+        }
+    }
+}
+
+/// Whether `presented` is `secret`, in constant time.
+pub fn matches(secret: &[u8], presented: &[u8]) -> bool {
+    let (secret, presented) = (Sha256::digest(secret), Sha256::digest(presented));
+    secret.as_slice().ct_eq(presented.as_slice()).into()
+}
@@ -0,0 +1,479 @@
+use std::{
+    cmp::Ordering,
+    io,
+    ops::Range,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use regex::Regex;
+use serde::{de::Error as _, Deserialize, Deserializer};
+
+use crate::{
+    compose::Project, image::ImageRef, registry::Registries, Config, Error, ManagedComposition,
+};
+
+/// Moves a service's image tag forward, by rewriting the variable its compose
+/// file takes the tag from, like `image: ghcr.io/me/app:${APP_TAG}`.
+#[derive(serde::Deserialize)]
+pub struct TagTracking {
+    /// The variable holding the tag
+    variable: String,
+    /// The file that variable lives in, relative to `work`. By default, the
+    /// last of the composition's `env_files` that sets it, or `.env`.
+    env_file: Option<PathBuf>,
+    #[serde(flatten)]
+    policy: Policy,
+}
+
+/// Which newer tags a service may move to.
+#[derive(serde::Deserialize)]
+#[serde(tag = "policy", rename_all = "lowercase")]
+pub enum Policy {
+    /// `1.4.2` to `1.4.3`, but not `1.5.0`
+    Patch,
+    /// `1.4.2` to `1.5.0`, but not `2.0.0`
+    Minor,
+    /// Any newer version
+    Major,
+    /// The newest tag matching `pattern`, by the version it starts with if
+    /// it has one, like `1.5` in `1.5-alpine`
+    Regex {
+        #[serde(deserialize_with = "deserialize_regex")]
+        pattern: Regex,
+    },
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(de: D) -> Result<Regex, D::Error> {
+    let pattern = String::deserialize(de)?;
+    Regex::new(&pattern).map_err(D::Error::custom)
+}
+
+/// A tag like `1.4`, `v2.0.1` or `3`, as comparable numbers.
+#[derive(PartialEq, Eq)]
+struct Version {
+    prefixed: bool,
+    parts: Vec<u64>,
+}
+
+impl Version {
+    /// Tags with suffixes like `-alpine` or `-rc1` aren't versions to us.
+    fn parse(tag: &str) -> Option<Self> {
+        Self::parse_leading(tag)
+            .filter(|(_, suffix)| suffix.is_empty())
+            .map(|(version, _)| version)
+    }
+
+    /// The version `tag` starts with, and whatever follows it.
+    fn parse_leading(tag: &str) -> Option<(Self, &str)> {
+        let (prefixed, rest) = match tag.strip_prefix('v') {
+            Some(rest) => (true, rest),
+            None => (false, tag),
+        };
+        let end = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let (numbers, suffix) = rest.split_at(end);
+        let parts = numbers
+            .split('.')
+            .map(|part| part.parse().ok())
+            .collect::<Option<Vec<u64>>>()?;
+        (parts.len() <= 3).then_some((Self { prefixed, parts }, suffix))
+    }
+
+    fn part(&self, index: usize) -> u64 {
+        self.parts.get(index).copied().unwrap_or(0)
+    }
+
+    /// Whether this looks the same as `other`, so `1.4` never becomes `1.5.0`.
+    fn same_shape(&self, other: &Self) -> bool {
+        self.prefixed == other.prefixed && self.parts.len() == other.parts.len()
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (0..3)
+            .map(|i| self.part(i).cmp(&other.part(i)))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+impl Policy {
+    /// Whether a service on `current` may move to `candidate`, if it's newer.
+    fn allows(&self, current: &str, candidate: &str) -> bool {
+        let semver = |allows: fn(&Version, &Version) -> bool| match (
+            Version::parse(current),
+            Version::parse(candidate),
+        ) {
+            (Some(current), Some(candidate)) => allows(&current, &candidate),
+            _ => false,
+        };
+        match self {
+            Self::Patch => semver(|current, candidate| {
+                candidate.same_shape(current)
+                    && candidate.part(0) == current.part(0)
+                    && candidate.part(1) == current.part(1)
+            }),
+            Self::Minor => semver(|current, candidate| {
+                candidate.same_shape(current) && candidate.part(0) == current.part(0)
+            }),
+            Self::Major => semver(|current, candidate| candidate.same_shape(current)),
+            Self::Regex { pattern } => pattern.is_match(candidate),
+        }
+    }
+
+    /// The newest of `tags` a service on `current` may move to, if any is
+    /// newer than `current`.
+    fn newest(&self, current: &str, tags: Vec<String>) -> Option<String> {
+        tags.into_iter()
+            .filter(|tag| self.allows(current, tag))
+            .filter(|tag| newer(tag, current) == Ordering::Greater)
+            .max_by(|a, b| newer(a, b))
+    }
+}
+
+/// Orders tags by the version they start with, then as text, with tags that
+/// start with a version after those that don't.
+fn newer(a: &str, b: &str) -> Ordering {
+    let version = |tag| Version::parse_leading(tag).map(|(version, _)| version);
+    version(a).cmp(&version(b)).then_with(|| a.cmp(b))
+}
+
+/// Tags are only ever moved by the registry poller, so tracking them without
+/// `poll_interval` would silently do nothing.
+pub fn validate(config: &Config) -> Result<(), String> {
+    if config.poll_interval.is_some() {
+        return Ok(());
+    }
+    match config.extra.iter().find(|(_, c)| !c.tags.is_empty()) {
+        Some((name, _)) => Err(format!("{name} tracks tags, which needs poll_interval")),
+        None => Ok(()),
+    }
+}
+
+/// Point `service` at the newest tag its policy allows, if that's newer than
+/// the one it's on. Returns the old and new tags if it moved.
+pub async fn advance(
+    registries: &Registries,
+    composition: &ManagedComposition,
+    project: &Project,
+    service: &str,
+    tracking: &TagTracking,
+) -> Result<Option<(String, String)>, Error> {
+    let (path, env, current_tag) = find_variable(composition, tracking)?;
+    let semver = !matches!(tracking.policy, Policy::Regex { .. });
+    if semver && Version::parse(&current_tag).is_none() {
+        return Err(Error::TagTracking(format!(
+            "{current_tag} is not a version"
+        )));
+    }
+    let image = project
+        .services
+        .get(service)
+        .and_then(|service| service.image.as_deref())
+        .map(ImageRef::parse)
+        .ok_or_else(|| Error::TagTracking(format!("{service} has no image")))?;
+
+    let tags = registries.tags(&image).await?;
+    let Some(tag) = tracking.policy.newest(&current_tag, tags) else {
+        return Ok(None);
+    };
+    replace(&path, &write_variable(&env, &tracking.variable, &tag))?;
+    Ok(Some((current_tag, tag)))
+}
+
+/// The env file that sets the tracked variable, its contents, and the tag.
+fn find_variable(
+    composition: &ManagedComposition,
+    tracking: &TagTracking,
+) -> Result<(PathBuf, String, String), Error> {
+    let files = match &tracking.env_file {
+        Some(file) => vec![file.clone()],
+        None if composition.env_files.is_empty() => vec![PathBuf::from(".env")],
+        None => composition.env_files.iter().map(PathBuf::from).collect(),
+    };
+    // Compose lets later env files override earlier ones
+    for file in files.iter().rev() {
+        let path = Path::new(&composition.work).join(file);
+        let env = std::fs::read_to_string(&path)?;
+        if let Some(tag) = read_variable(&env, &tracking.variable) {
+            return Ok((path, env, tag));
+        }
+    }
+    Err(Error::TagTracking(format!(
+        "{} is not set in {}",
+        tracking.variable,
+        files
+            .iter()
+            .map(|file| file.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    )))
+}
+
+/// Write `contents` aside and rename it over `path`, so compose never sees
+/// half a file, keeping the file's mode and owner.
+fn replace(path: &Path, contents: &str) -> io::Result<()> {
+    let metadata = std::fs::metadata(path)?;
+    let temporary = path.with_extension("conductor-tmp");
+    let written = std::fs::write(&temporary, contents)
+        .and_then(|()| std::fs::set_permissions(&temporary, metadata.permissions()))
+        .and_then(|()| {
+            std::os::unix::fs::chown(&temporary, Some(metadata.uid()), Some(metadata.gid()))
+        })
+        .and_then(|()| std::fs::rename(&temporary, path));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temporary);
+    }
+    written
+}
+
+/// The value of `NAME=value` or `export NAME="value"` in a `.env` file.
+fn read_variable(env: &str, name: &str) -> Option<String> {
+    env.lines()
+        .find_map(|line| assignment(line, name))
+        .map(|value| value[value_span(value)].to_owned())
+}
+
+/// `env` with `name` set to `value`, appended if it wasn't there before. Any
+/// `export`, quotes and comment on the line stay as they were.
+fn write_variable(env: &str, name: &str, value: &str) -> String {
+    let mut found = false;
+    let mut out = String::new();
+    for line in env.lines() {
+        if let (false, Some(old)) = (found, assignment(line, name)) {
+            found = true;
+            let start = line.len() - old.len();
+            let span = value_span(old);
+            out.push_str(&line[..start + span.start]);
+            out.push_str(value);
+            out.push_str(&line[start + span.end..]);
+        } else {
+            out.push_str(line);
+        }
+        out.push('\n');
+    }
+    if !found {
+        out.push_str(&format!("{name}={value}\n"));
+    }
+    out
+}
+
+/// The value part of `line`, if it assigns `name`.
+fn assignment<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let line = line.trim_start();
+    let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+    let (key, value) = line.split_once('=')?;
+    (key.trim() == name).then_some(value)
+}
+
+/// Where the value itself is in an assignment's value part, inside any quotes
+/// and before any ` # comment`.
+fn value_span(value: &str) -> Range<usize> {
+    let start = value.len() - value.trim_start().len();
+    let rest = &value[start..];
+    if let Some(quote) = rest.chars().next().filter(|c| matches!(c, '"' | '\'')) {
+        let end = rest[1..].find(quote).unwrap_or(rest.len() - 1);
+        return start + 1..start + 1 + end;
+    }
+    let end = rest
+        .find(" #")
+        .or_else(|| rest.find("\t#"))
+        .unwrap_or(rest.len());
+    start..start + rest[..end].trim_end().len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(toml: &str) -> Policy {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| (*tag).to_owned()).collect()
+    }
+
+    #[test]
+    fn versions_are_parsed() {
+        let version = Version::parse("v1.4.2").unwrap();
+        assert!(version.prefixed);
+        assert_eq!(version.parts, [1, 4, 2]);
+        assert_eq!(Version::parse("3").unwrap().parts, [3]);
+        assert!(Version::parse("1.4-alpine").is_none());
+        assert!(Version::parse("latest").is_none());
+        assert!(Version::parse("1.2.3.4").is_none());
+        assert!(Version::parse("1..2").is_none());
+        assert!(Version::parse("").is_none());
+    }
+
+    #[test]
+    fn suffixes_are_split_off() {
+        let (version, suffix) = Version::parse_leading("1.5-alpine").unwrap();
+        assert_eq!(
+            (version.parts.as_slice(), suffix),
+            ([1, 5].as_slice(), "-alpine")
+        );
+        assert!(Version::parse_leading("alpine").is_none());
+        assert!(Version::parse("1.10").unwrap() > Version::parse("1.9").unwrap());
+    }
+
+    #[test]
+    fn policies_allow_their_versions() {
+        let (patch, minor, major) = (
+            policy(r#"policy = "patch""#),
+            policy(r#"policy = "minor""#),
+            policy(r#"policy = "major""#),
+        );
+        assert!(patch.allows("1.4.2", "1.4.3"));
+        assert!(!patch.allows("1.4.2", "1.5.0"));
+        assert!(minor.allows("1.4.2", "1.5.0"));
+        assert!(!minor.allows("1.4.2", "2.0.0"));
+        assert!(major.allows("1.4.2", "2.0.0"));
+        // Neither a different shape, nor a suffix, nor a prefix that wasn't there
+        assert!(!major.allows("1.4", "1.5.0"));
+        assert!(!major.allows("1.4", "1.5-alpine"));
+        assert!(!major.allows("1.4", "v1.5"));
+        assert!(!major.allows("latest", "1.5"));
+    }
+
+    #[test]
+    fn semver_policies_pick_the_newest_allowed_version() {
+        let minor = policy(r#"policy = "minor""#);
+        let available = tags(&["1.4.1", "1.4.10", "1.9.0", "1.10.0", "2.0.0", "latest"]);
+        assert_eq!(
+            minor.newest("1.4.2", available.clone()).as_deref(),
+            Some("1.10.0")
+        );
+        assert_eq!(minor.newest("1.10.0", available), None);
+    }
+
+    #[test]
+    fn regex_policies_pick_suffixed_tags() {
+        let alpine = policy(
+            r#"policy = "regex"
+pattern = '^1\.\d+-alpine$'"#,
+        );
+        let available = tags(&[
+            "1.4-alpine",
+            "1.10-alpine",
+            "1.9-alpine",
+            "1.11",
+            "2.0-alpine",
+        ]);
+        assert_eq!(
+            alpine.newest("1.4-alpine", available.clone()).as_deref(),
+            Some("1.10-alpine")
+        );
+        assert_eq!(
+            alpine.newest("latest", available.clone()).as_deref(),
+            Some("1.10-alpine")
+        );
+        assert_eq!(alpine.newest("1.10-alpine", available), None);
+    }
+
+    #[test]
+    fn regex_policies_order_other_tags_as_text() {
+        let nightly = policy(
+            r#"policy = "regex"
+pattern = '^nightly-'"#,
+        );
+        let available = tags(&["nightly-20261001", "nightly-20261003", "stable"]);
+        assert_eq!(
+            nightly.newest("nightly-20261002", available).as_deref(),
+            Some("nightly-20261003")
+        );
+    }
+
+    #[test]
+    fn variables_keep_their_quotes_and_comments() {
+        let env =
+            "# tags\nexport APP_TAG=\"1.4\" # pinned by conductor\nDB_TAG='16' \nOTHER=x #y\n";
+        assert_eq!(read_variable(env, "APP_TAG").as_deref(), Some("1.4"));
+        assert_eq!(read_variable(env, "DB_TAG").as_deref(), Some("16"));
+        assert_eq!(read_variable(env, "OTHER").as_deref(), Some("x"));
+        assert_eq!(
+            write_variable(env, "APP_TAG", "1.5"),
+            "# tags\nexport APP_TAG=\"1.5\" # pinned by conductor\nDB_TAG='16' \nOTHER=x #y\n"
+        );
+        assert_eq!(
+            write_variable(env, "OTHER", "z"),
+            "# tags\nexport APP_TAG=\"1.4\" # pinned by conductor\nDB_TAG='16' \nOTHER=z #y\n"
+        );
+        assert_eq!(write_variable("A=1", "B", "2"), "A=1\nB=2\n");
+    }
+
+    #[test]
+    fn variables_are_found_in_the_last_env_file_setting_them() {
+        let work = std::env::temp_dir().join(format!("conductor-env-{}", std::process::id()));
+        std::fs::create_dir_all(&work).unwrap();
+        std::fs::write(work.join(".env"), "APP_TAG=1.4\nDB_TAG=16\n").unwrap();
+        std::fs::write(work.join(".env.prod"), "APP_TAG=1.5\n").unwrap();
+        let composition: ManagedComposition = toml::from_str(&format!(
+            "work = {:?}\nenv_files = [\".env\", \".env.prod\"]",
+            work.display().to_string()
+        ))
+        .unwrap();
+        let tracking = |toml: &str| -> TagTracking {
+            toml::from_str(&format!("{toml}\npolicy = \"minor\"")).unwrap()
+        };
+        let found = |tracking: TagTracking| find_variable(&composition, &tracking);
+        let (path, _, tag) = found(tracking(r#"variable = "APP_TAG""#)).unwrap();
+        let app = (path, tag);
+        let (path, _, tag) = found(tracking(r#"variable = "DB_TAG""#)).unwrap();
+        let db = (path, tag);
+        let (path, _, tag) = found(tracking(
+            r#"variable = "APP_TAG"
+env_file = ".env""#,
+        ))
+        .unwrap();
+        let pinned = (path, tag);
+        let missing = found(tracking(r#"variable = "WEB_TAG""#)).is_err();
+        std::fs::remove_dir_all(&work).unwrap();
+        assert_eq!(app, (work.join(".env.prod"), "1.5".to_owned()));
+        assert_eq!(db, (work.join(".env"), "16".to_owned()));
+        assert_eq!(pinned, (work.join(".env"), "1.4".to_owned()));
+        assert!(missing);
+    }
+
+    #[test]
+    fn replacing_keeps_the_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("conductor-tags-{}.env", std::process::id()));
+        std::fs::write(&path, "APP_TAG=1.4\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        replace(&path, "APP_TAG=1.5\n").unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o640);
+        assert_eq!(contents, "APP_TAG=1.5\n");
+    }
+
+    #[test]
+    fn tracking_needs_the_poller() {
+        let compositions = r#"
+[web]
+work = "/srv/web"
+
+[web.tags.app]
+variable = "APP_TAG"
+policy = "minor"
+"#;
+        let unpolled: Config = toml::from_str(compositions).unwrap();
+        let polled: Config =
+            toml::from_str(&format!("poll_interval = 60\n{compositions}")).unwrap();
+        assert!(validate(&unpolled).is_err());
+        assert!(validate(&polled).is_ok());
+    }
+}
@@ -0,0 +1,468 @@
+use std::{
+    fs::File,
+    future::IntoFuture,
+    io::BufReader,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    extract::State,
+    http::{header, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    Router,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    select,
+    task::JoinSet,
+    time::MissedTickBehavior,
+};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring, pki_types::CertificateDer, server::WebPkiClientVerifier, RootCertStore,
+        ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+use x509_parser::{
+    certificate::X509Certificate, extensions::GeneralName, prelude::FromDer,
+    x509::AttributeTypeAndValue,
+};
+
+use crate::{
+    auth::{self, ClientIdentity, Identity},
+    image::glob,
+    Config,
+};
+
+/// How long a client gets to finish its TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serve HTTPS with a certificate and key in PEM files, like the ones certbot
+/// writes. Both are reloaded whenever either file changes.
+#[derive(Clone, serde::Deserialize)]
+pub struct TlsSettings {
+    cert: PathBuf,
+    key: PathBuf,
+    /// A port to redirect plain HTTP requests to HTTPS from, on every address
+    /// HTTPS is served on
+    pub redirect_port: Option<u16>,
+    /// Seconds between checks of whether the files changed
+    #[serde(default = "default_reload_interval")]
+    reload_interval: u64,
+    /// CAs whose client certificates are let in without a token
+    client_ca: Option<PathBuf>,
+    /// What clients may do, by their certificates
+    #[serde(default)]
+    clients: Vec<ClientRule>,
+}
+
+/// Gives client certificates matching every pattern set here some scopes,
+/// like a named token. Patterns may contain `*` wildcards, and match if any
+/// of the certificate's values of that kind match.
+#[derive(Clone, serde::Deserialize)]
+pub struct ClientRule {
+    /// The subject's common name, like `runner-*`
+    cn: Option<String>,
+    /// The subject's organization
+    o: Option<String>,
+    /// The subject's organizational unit
+    ou: Option<String>,
+    /// Any DNS name, email address or URI among the subject alternative names
+    san: Option<String>,
+    scopes: Vec<String>,
+}
+
+impl ClientRule {
+    fn is_empty(&self) -> bool {
+        self.cn.is_none() && self.o.is_none() && self.ou.is_none() && self.san.is_none()
+    }
+
+    fn matches(&self, client: &ClientCertificate) -> bool {
+        let matches = |pattern: &Option<String>, values: &[String]| {
+            pattern
+                .as_ref()
+                .is_none_or(|pattern| values.iter().any(|value| glob(pattern, value)))
+        };
+        !self.is_empty()
+            && matches(&self.cn, &client.common_names)
+            && matches(&self.o, &client.organizations)
+            && matches(&self.ou, &client.units)
+            && matches(&self.san, &client.names)
+    }
+}
+
+/// Check that client rules can match something, and only grant real scopes.
+pub fn validate(config: &Config) -> Result<(), String> {
+    let Some(settings) = &config.tls else {
+        return Ok(());
+    };
+    if !settings.clients.is_empty() && settings.client_ca.is_none() {
+        return Err("tls.clients needs a tls.client_ca to check certificates with".to_owned());
+    }
+    for (number, rule) in settings.clients.iter().enumerate() {
+        let owner = format!("tls client rule {}", number + 1);
+        if rule.is_empty() {
+            return Err(format!("{owner} needs a cn, o, ou or san"));
+        }
+        auth::check_scopes(&owner, &rule.scopes)?;
+    }
+    Ok(())
+}
+
+/// The parts of a client certificate rules are matched against.
+struct ClientCertificate {
+    /// The whole subject, to name certificates without a common name by
+    subject: String,
+    common_names: Vec<String>,
+    organizations: Vec<String>,
+    units: Vec<String>,
+    names: Vec<String>,
+}
+
+impl ClientCertificate {
+    fn parse(der: &CertificateDer) -> Option<Self> {
+        let (_, certificate) = X509Certificate::from_der(der).ok()?;
+        let subject = certificate.subject();
+        let names = certificate
+            .subject_alternative_name()
+            .ok()
+            .flatten()
+            .map(|extension| {
+                extension
+                    .value
+                    .general_names
+                    .iter()
+                    .filter_map(|name| match name {
+                        GeneralName::DNSName(name)
+                        | GeneralName::RFC822Name(name)
+                        | GeneralName::URI(name) => Some((*name).to_owned()),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Some(Self {
+            subject: subject.to_string(),
+            common_names: strings(subject.iter_common_name()),
+            organizations: strings(subject.iter_organization()),
+            units: strings(subject.iter_organizational_unit()),
+            names,
+        })
+    }
+
+    /// Who this is, with the scopes of every rule it matches, if any match.
+    fn identify(&self, rules: &[ClientRule]) -> Option<ClientIdentity> {
+        let scopes: Vec<String> = rules
+            .iter()
+            .filter(|rule| rule.matches(self))
+            .flat_map(|rule| rule.scopes.iter().cloned())
+            .collect();
+        if scopes.is_empty() {
+            return None;
+        }
+        let name = self.common_names.first().unwrap_or(&self.subject);
+        let identity = Identity::new(format!("client:{name}"), scopes);
+        Some(ClientIdentity(Arc::new(identity)))
+    }
+}
+
+/// Attributes that aren't strings can't match a pattern anyway.
+fn strings<'a>(attributes: impl Iterator<Item = &'a AttributeTypeAndValue<'a>>) -> Vec<String> {
+    attributes
+        .filter_map(|attribute| attribute.as_str().ok())
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+fn default_reload_interval() -> u64 {
+    60
+}
+
+impl TlsSettings {
+    fn modified(&self) -> Result<Vec<SystemTime>, String> {
+        [Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|path| {
+                std::fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .map_err(|e| format!("{}: {e}", path.display()))
+            })
+            .collect()
+    }
+
+    fn load(&self) -> Result<ServerConfig, String> {
+        let open = |path: &Path| {
+            File::open(path)
+                .map(BufReader::new)
+                .map_err(|e| format!("{}: {e}", path.display()))
+        };
+        let certs = rustls_pemfile::certs(&mut open(&self.cert)?)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("{}: {e}", self.cert.display()))?;
+        let key = rustls_pemfile::private_key(&mut open(&self.key)?)
+            .map_err(|e| format!("{}: {e}", self.key.display()))?
+            .ok_or_else(|| format!("{} has no private key", self.key.display()))?;
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?;
+        let builder = match &self.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for ca in rustls_pemfile::certs(&mut open(client_ca)?) {
+                    let ca = ca.map_err(|e| format!("{}: {e}", client_ca.display()))?;
+                    roots
+                        .add(ca)
+                        .map_err(|e| format!("{}: {e}", client_ca.display()))?;
+                }
+                // Clients without a certificate can still use a token
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+                    .allow_unauthenticated()
+                    .build()
+                    .map_err(|e| format!("{}: {e}", client_ca.display()))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .map_err(|e| e.to_string())?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+/// Terminates TLS with whichever certificate is current, swapped out when
+/// its files change.
+pub struct Tls {
+    settings: TlsSettings,
+    config: RwLock<Arc<ServerConfig>>,
+    /// When `cert`, `key` and `client_ca` were last changed, as of the last load
+    modified: RwLock<Vec<SystemTime>>,
+}
+
+impl Tls {
+    pub fn new(settings: &TlsSettings) -> Result<Self, String> {
+        Ok(Self {
+            modified: RwLock::new(settings.modified()?),
+            config: RwLock::new(Arc::new(settings.load()?)),
+            settings: settings.clone(),
+        })
+    }
+
+    /// Load the certificate again if its files changed since the last load.
+    /// A half-renewed or broken certificate leaves the old one in place.
+    fn reload(&self) -> Result<bool, String> {
+        let modified = self.settings.modified()?;
+        if *self.modified.read().unwrap_or_else(|e| e.into_inner()) == modified {
+            return Ok(false);
+        }
+        let config = Arc::new(self.settings.load()?);
+        *self.config.write().unwrap_or_else(|e| e.into_inner()) = config;
+        *self.modified.write().unwrap_or_else(|e| e.into_inner()) = modified;
+        Ok(true)
+    }
+
+    /// Finish the handshake with a client, and find who its certificate
+    /// belongs to if it sent one.
+    pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: S,
+        peer: &str,
+    ) -> Option<(TlsStream<S>, Option<ClientIdentity>)> {
+        let config = self
+            .config
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        let handshake = TlsAcceptor::from(config).accept(stream);
+        let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(source)) => {
+                eprintln!("TLS handshake with {peer} failed: {source}");
+                return None;
+            }
+            Err(_) => return None,
+        };
+        // The verifier already checked the certificate against `client_ca`
+        let client = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|chain| chain.first())
+            .and_then(ClientCertificate::parse)
+            .and_then(|client| client.identify(&self.settings.clients));
+        Some((stream, client))
+    }
+}
+
+/// Every `reload_interval`, check whether the certificate's files changed, and
+/// load them again if they did.
+pub async fn reload_on_change(tls: Arc<Tls>) {
+    let interval = Duration::from_secs(tls.settings.reload_interval.max(1));
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = ticker.tick() => {}
+        }
+        match tls.reload() {
+            Ok(true) => println!("Reloaded TLS certificate {}", tls.settings.cert.display()),
+            Ok(false) => {}
+            Err(message) => eprintln!("Error reloading TLS certificate: {message}"),
+        }
+    }
+}
+
+/// Answer plain HTTP on `addresses` with a redirect to the same path over
+/// HTTPS on `https_port`.
+pub async fn redirect(addresses: Vec<SocketAddr>, https_port: u16) {
+    let app = Router::new().fallback(redirect_web).with_state(https_port);
+    let mut servers = JoinSet::new();
+    for address in addresses {
+        let tcp = match TcpListener::bind(address).await {
+            Ok(tcp) => tcp,
+            Err(source) => {
+                eprintln!("Error binding redirect address {address}: {source:?}");
+                continue;
+            }
+        };
+        let server = axum::serve(tcp, app.clone()).with_graceful_shutdown(vss::shutdown_signal());
+        servers.spawn(server.into_future());
+    }
+    while let Some(served) = servers.join_next().await {
+        if let Ok(Err(source)) = served {
+            eprintln!("Error: {source:?}");
+        }
+    }
+}
+
+async fn redirect_web(
+    State(https_port): State<u16>,
+    headers: axum::http::HeaderMap,
+    uri: Uri,
+) -> Response {
+    let Some(host) = headers
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return (StatusCode::BAD_REQUEST, "Missing Host header\n").into_response();
+    };
+    // Drop the port the request came in on, keeping IPv6 addresses whole
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    };
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let target = if https_port == 443 {
+        format!("https://{host}{path}")
+    } else {
+        format!("https://{host}:{https_port}{path}")
+    };
+    Redirect::permanent(&target).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Scope;
+
+    /// O=Example, OU=ci, OU=deploy, CN=runner-1, with the SANs
+    /// runner-1.ci.example.com and ci@example.com
+    const RUNNER: &str = "-----BEGIN CERTIFICATE-----
+MIICEjCCAbmgAwIBAgIUGC9WNHWufuPxBsxL8I/zswJGqyswCgYIKoZIzj0EAwIw
+QzEQMA4GA1UECgwHRXhhbXBsZTELMAkGA1UECwwCY2kxDzANBgNVBAsMBmRlcGxv
+eTERMA8GA1UEAwwIcnVubmVyLTEwIBcNMjYxMDE1MDkxNDE3WhgPMjEyNjA5MjEw
+OTE0MTdaMEMxEDAOBgNVBAoMB0V4YW1wbGUxCzAJBgNVBAsMAmNpMQ8wDQYDVQQL
+DAZkZXBsb3kxETAPBgNVBAMMCHJ1bm5lci0xMFkwEwYHKoZIzj0CAQYIKoZIzj0D
+AQcDQgAEun11rwq+z2dtmlEsVGswFQ2oDBveCqZ7/5Ur+WXJeZJu6/kVimHcDr/V
+2XSE7y+Yhbo4rniWs+3JP4my2uAzvqOBiDCBhTAdBgNVHQ4EFgQU9TcCVPhzmAY9
+vt3K4NreuM/HO40wHwYDVR0jBBgwFoAU9TcCVPhzmAY9vt3K4NreuM/HO40wDwYD
+VR0TAQH/BAUwAwEB/zAyBgNVHREEKzApghdydW5uZXItMS5jaS5leGFtcGxlLmNv
+bYEOY2lAZXhhbXBsZS5jb20wCgYIKoZIzj0EAwIDRwAwRAIgIoa7NLxzWH/pB29v
+iXaoy9sBFBbYQFKN2ZhoqgLyHTYCIEHOOMLjERIliMgmm/W8328EScIgAF+KIw8f
+WwciFFSC
+-----END CERTIFICATE-----";
+
+    /// O="CN=runner-1, O=Example", CN=mallory
+    const IMPOSTOR: &str = "-----BEGIN CERTIFICATE-----
+MIIBvDCCAWOgAwIBAgIURwE0CUAfKA2ktxPur9mfosTfXw0wCgYIKoZIzj0EAwIw
+MzEfMB0GA1UECgwWQ049cnVubmVyLTEsIE89RXhhbXBsZTEQMA4GA1UEAwwHbWFs
+bG9yeTAgFw0yNjEwMTUwOTE0MTdaGA8yMTI2MDkyMTA5MTQxN1owMzEfMB0GA1UE
+CgwWQ049cnVubmVyLTEsIE89RXhhbXBsZTEQMA4GA1UEAwwHbWFsbG9yeTBZMBMG
+ByqGSM49AgEGCCqGSM49AwEHA0IABJkmURQ0xXVII+kz3JC21JYs/KmRh9rqQjBz
+uGYS2/zYqPEr/N8jGjsF38sZKzpVsn98PfvVqGwK99xpj4vrozyjUzBRMB0GA1Ud
+DgQWBBS0OK7UAY5jxLJ+VRBbbCgCxioSSzAfBgNVHSMEGDAWgBS0OK7UAY5jxLJ+
+VRBbbCgCxioSSzAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0cAMEQCIDip
+WBHi7e+I0wE0CuT3Bg6xpKJmgu11cNfixcPRlAIlAiBb/ZEEg2K84s71csTl89Ao
+fbaHmoJAEsaBq1+lldCQOQ==
+-----END CERTIFICATE-----";
+
+    fn certificate(pem: &str) -> ClientCertificate {
+        let der = rustls_pemfile::certs(&mut pem.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap();
+        ClientCertificate::parse(&der).unwrap()
+    }
+
+    fn rule(toml: &str) -> ClientRule {
+        toml::from_str(&format!("{toml}\nscopes = [\"status:prod\"]")).unwrap()
+    }
+
+    #[test]
+    fn attributes_are_parsed_separately() {
+        let runner = certificate(RUNNER);
+        assert_eq!(runner.common_names, ["runner-1"]);
+        assert_eq!(runner.organizations, ["Example"]);
+        assert_eq!(runner.units, ["ci", "deploy"]);
+        assert_eq!(runner.names, ["runner-1.ci.example.com", "ci@example.com"]);
+    }
+
+    #[test]
+    fn every_pattern_has_to_match() {
+        let runner = certificate(RUNNER);
+        assert!(rule(r#"cn = "runner-*""#).matches(&runner));
+        assert!(rule(
+            r#"cn = "runner-*"
+o = "Example""#
+        )
+        .matches(&runner));
+        assert!(!rule(
+            r#"cn = "runner-*"
+o = "Other""#
+        )
+        .matches(&runner));
+        assert!(rule(r#"ou = "deploy""#).matches(&runner));
+        assert!(!rule(r#"ou = "ops""#).matches(&runner));
+        assert!(rule(r#"san = "*.ci.example.com""#).matches(&runner));
+        assert!(rule(r#"san = "ci@example.com""#).matches(&runner));
+        assert!(!rule(r#"cn = "runner""#).matches(&runner));
+    }
+
+    #[test]
+    fn attributes_dont_leak_into_each_other() {
+        let impostor = certificate(IMPOSTOR);
+        assert!(!rule(r#"cn = "runner-*""#).matches(&impostor));
+        assert!(!rule(r#"o = "Example""#).matches(&impostor));
+        assert!(!rule(r#"cn = "*runner-1*""#).matches(&impostor));
+    }
+
+    #[test]
+    fn identities_are_named_by_common_name() {
+        let rules = [rule(r#"cn = "runner-*""#), rule(r#"o = "Other""#)];
+        let identity = certificate(RUNNER).identify(&rules).unwrap().0;
+        assert_eq!(identity.name, "client:runner-1");
+        assert!(identity.may(Scope::Status("prod")));
+        assert!(!identity.may(Scope::Restart("prod")));
+        assert!(certificate(IMPOSTOR).identify(&rules).is_none());
+    }
+}
@@ -0,0 +1,133 @@
+use std::sync::Arc;
+
+use crate::{
+    accept,
+    secret::{self, Secret},
+    AppState, Error,
+};
+use axum::{
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Which forge events are allowed to redeploy a composition.
+///
+/// An event that doesn't match any of these lists is acknowledged but ignored.
+#[derive(serde::Deserialize)]
+pub struct WebhookTrigger {
+    secret: Secret,
+    #[serde(default)]
+    branches: Vec<String>,
+    #[serde(default)]
+    images: Vec<String>,
+    #[serde(default)]
+    workflows: Vec<String>,
+}
+
+impl WebhookTrigger {
+    pub fn secret(&self) -> &[u8] {
+        self.secret.expose().as_bytes()
+    }
+
+    pub fn matches_branch(&self, branch: &str) -> bool {
+        self.branches.iter().any(|b| b == branch)
+    }
+
+    /// Workflows match by name. If any branches are configured, the workflow
+    /// must also have run on one of them.
+    pub fn matches_workflow(&self, workflow: &str, branch: Option<&str>) -> bool {
+        let branch_ok = self.branches.is_empty() || branch.is_some_and(|b| self.matches_branch(b));
+        branch_ok && self.workflows.iter().any(|w| w == workflow)
+    }
+
+    /// Images are written as `repository` or `repository:tag`, where the
+    /// repository may or may not include the registry host.
+    pub fn matches_image(&self, candidates: &[&str], tag: Option<&str>) -> bool {
+        self.images.iter().any(|image| {
+            let (repo, want_tag) = split_tag(image);
+            let repo_ok = candidates.iter().any(|c| c.eq_ignore_ascii_case(repo));
+            let tag_ok = want_tag.is_none_or(|want| tag == Some(want));
+            repo_ok && tag_ok
+        })
+    }
+}
+
+/// Split `repo:tag` into its parts, taking care not to mistake a registry port for a tag.
+fn split_tag(image: &str) -> (&str, Option<&str>) {
+    match image.rsplit_once(':') {
+        Some((repo, tag)) if !tag.contains('/') => (repo, Some(tag)),
+        _ => (image, None),
+    }
+}
+
+/// Check a hex-encoded HMAC-SHA256 of `body` in constant time.
+pub fn verify_hmac_sha256(secret: &[u8], body: &[u8], signature: &str) -> Result<(), Error> {
+    let signature = hex::decode(signature.trim()).map_err(|_| Error::InvalidSignature)?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).map_err(|_| Error::InvalidSignature)?;
+    mac.update(body);
+    mac.verify_slice(&signature)
+        .map_err(|_| Error::InvalidSignature)
+}
+
+/// Check a shared-secret token header in constant time.
+pub fn verify_token(secret: &[u8], token: &str) -> Result<(), Error> {
+    if secret::matches(secret, token.as_bytes()) {
+        Ok(())
+    } else {
+        Err(Error::InvalidSignature)
+    }
+}
+
+pub fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Queue a redeploy as a job after a webhook matched, or acknowledge it if it
+/// didn't. Forges give up on deliveries after a few seconds, so this doesn't
+/// wait for it.
+pub fn accept_if(matched: bool, name: &str, state: &Arc<AppState>) -> Result<Response, Error> {
+    if !matched {
+        return Ok((StatusCode::OK, "Ignored\n").into_response());
+    }
+    accept(name, None, state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example from GitHub's webhook documentation.
+    const SECRET: &[u8] = b"It's a Secret to Everybody";
+    const SIGNATURE: &str = "757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+    #[test]
+    fn hmac_signatures_are_checked() {
+        assert!(verify_hmac_sha256(SECRET, b"Hello, World!", SIGNATURE).is_ok());
+        assert!(verify_hmac_sha256(SECRET, b"Hello, World!", &format!(" {SIGNATURE}\n")).is_ok());
+        assert!(verify_hmac_sha256(SECRET, b"Hello, World?", SIGNATURE).is_err());
+        assert!(verify_hmac_sha256(b"another secret", b"Hello, World!", SIGNATURE).is_err());
+        assert!(verify_hmac_sha256(SECRET, b"Hello, World!", &SIGNATURE[..62]).is_err());
+        assert!(verify_hmac_sha256(SECRET, b"Hello, World!", "not hex").is_err());
+        assert!(verify_hmac_sha256(SECRET, b"Hello, World!", "").is_err());
+    }
+
+    #[test]
+    fn tags_are_split_off() {
+        assert_eq!(split_tag("nginx"), ("nginx", None));
+        assert_eq!(split_tag("nginx:1.27"), ("nginx", Some("1.27")));
+        assert_eq!(
+            split_tag("ghcr.io/me/app:v2"),
+            ("ghcr.io/me/app", Some("v2"))
+        );
+        assert_eq!(
+            split_tag("localhost:5000/app"),
+            ("localhost:5000/app", None)
+        );
+        assert_eq!(
+            split_tag("localhost:5000/app:1.4"),
+            ("localhost:5000/app", Some("1.4"))
+        );
+    }
+}